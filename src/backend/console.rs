@@ -0,0 +1,154 @@
+use super::{BackendError, Channel, ChannelKind, ChatBackend, Event, EventHandler, User};
+use std::io::{self, BufRead};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+const SELF_ID: &'static str = "bot";
+const HEARTBEAT_INTERVAL: u64 = 1;
+
+struct ConsoleState {
+    user: String,
+    channel: String,
+    next_ts: u64,
+}
+
+/// A backend reading messages from stdin and writing responses to stdout.
+///
+/// Lines starting with `/user <name>` or `/channel <name>` change the identity
+/// the following lines are sent as.
+pub struct ConsoleBackend {
+    self_name: String,
+    state: Mutex<ConsoleState>,
+}
+
+impl ConsoleBackend {
+    pub fn new(self_name: String) -> ConsoleBackend {
+        ConsoleBackend {
+            self_name: self_name,
+            state: Mutex::new(ConsoleState {
+                                  user: "console".to_string(),
+                                  channel: "console".to_string(),
+                                  next_ts: 1,
+                              }),
+        }
+    }
+
+    fn handle_line(&self, line: &str) -> Option<Event> {
+        let mut state = self.state.lock().unwrap();
+        if line.starts_with("/user ") {
+            state.user = line["/user ".len()..].trim().to_string();
+            None
+        } else if line.starts_with("/channel ") {
+            state.channel = line["/channel ".len()..].trim().to_string();
+            None
+        } else {
+            let ts = format!("{}", state.next_ts);
+            state.next_ts += 1;
+            Some(Event::Message {
+                     user: state.user.clone(),
+                     channel: state.channel.clone(),
+                     text: line.to_string(),
+                     ts: ts,
+                     thread_ts: None,
+                 })
+        }
+    }
+}
+
+impl ChatBackend for ConsoleBackend {
+    fn run(&self, handler: &mut EventHandler) -> Result<(), BackendError> {
+        handler.on_connect(self);
+
+        // read stdin on a separate thread, so that heartbeats can be sent while waiting
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let result = loop {
+            match rx.recv_timeout(Duration::from_secs(HEARTBEAT_INTERVAL)) {
+                Ok(Ok(line)) => {
+                    if let Some(event) = self.handle_line(&line) {
+                        handler.on_event(self, event);
+                    }
+                }
+                Ok(Err(e)) => break Err(BackendError(format!("{}", e))),
+                Err(RecvTimeoutError::Timeout) => handler.on_event(self, Event::Heartbeat),
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            }
+        };
+
+        handler.on_close(self);
+        result
+    }
+
+    fn reconnect_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    fn send_message(&self, channel: &str, msg: &str) -> Result<(), BackendError> {
+        println!("#{} <{}> {}", channel, self.self_name, msg);
+        Ok(())
+    }
+
+    fn send_direct(&self, user: &str, msg: &str) -> Result<(), BackendError> {
+        println!("@{} <{}> {}", user, self.self_name, msg);
+        Ok(())
+    }
+
+    fn send_in_thread(&self,
+                      channel: &str,
+                      thread_ts: &str,
+                      msg: &str)
+                      -> Result<(), BackendError> {
+        println!("#{} [thread {}] <{}> {}", channel, thread_ts, self.self_name, msg);
+        Ok(())
+    }
+
+    fn add_reaction(&self, channel: &str, ts: &str, emoji: &str) -> Result<(), BackendError> {
+        println!("#{} [reaction to {}] :{}:", channel, ts, emoji.trim_matches(':'));
+        Ok(())
+    }
+
+    fn self_user(&self) -> Option<User> {
+        Some(User {
+                 id: SELF_ID.to_string(),
+                 name: self.self_name.clone(),
+                 is_primary_owner: false,
+                 is_owner: false,
+                 is_admin: false,
+             })
+    }
+
+    fn users(&self) -> Vec<User> {
+        let state = self.state.lock().unwrap();
+        // whoever sits at the console owns the bot
+        vec![User {
+                 id: state.user.clone(),
+                 name: state.user.clone(),
+                 is_primary_owner: true,
+                 is_owner: false,
+                 is_admin: false,
+             }]
+    }
+
+    fn channels(&self) -> Vec<Channel> {
+        let state = self.state.lock().unwrap();
+        vec![Channel {
+                 id: state.channel.clone(),
+                 name: state.channel.clone(),
+                 kind: ChannelKind::Public,
+             }]
+    }
+
+    fn channel_kind(&self, _: &str) -> ChannelKind {
+        ChannelKind::Public
+    }
+}
@@ -0,0 +1,170 @@
+mod console;
+mod slack;
+
+pub use self::console::ConsoleBackend;
+pub use self::slack::SlackBackend;
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub is_primary_owner: bool,
+    pub is_owner: bool,
+    pub is_admin: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub id: String,
+    /// For direct message channels, the name of the other user
+    pub name: String,
+    pub kind: ChannelKind,
+}
+
+/// Who can see a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelKind {
+    Public,
+    /// A private channel or group conversation
+    Private,
+    /// A direct message conversation with one user
+    Direct,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Message {
+        user: String,
+        channel: String,
+        text: String,
+        ts: String,
+        thread_ts: Option<String>,
+    },
+    /// A new user joined the team
+    UserJoined(User),
+    /// A user's name or flags changed
+    UserChanged(User),
+    ChannelCreated(Channel),
+    ChannelRenamed(Channel),
+    /// The bot joined a channel
+    ChannelJoined(Channel),
+    MessageChanged {
+        channel: String,
+        ts: String,
+        user: Option<String>,
+        text: Option<String>,
+    },
+    MessageDeleted { channel: String, ts: String },
+    /// An event the backend received but couldn't or wouldn't pass on
+    Dropped(DropReason),
+    /// Sent periodically by the backend, even when there is no other traffic
+    Heartbeat,
+}
+
+/// Why an inbound event was dropped
+#[derive(Clone, Debug)]
+pub enum DropReason {
+    /// Sent by a bot; contains the bot's name or ID, if known
+    BotMessage(Option<String>),
+    /// A message that isn't attributed to any user
+    NoUser,
+    /// A message with no text of its own, only attachments
+    AttachmentsOnly,
+    /// A required field was missing; describes what was being decoded
+    Malformed(&'static str),
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DropReason::BotMessage(Some(ref bot)) => write!(f, "message from bot {}", bot),
+            DropReason::BotMessage(None) => write!(f, "message from an unknown bot"),
+            DropReason::NoUser => write!(f, "message without a user"),
+            DropReason::AttachmentsOnly => write!(f, "message with only attachments"),
+            DropReason::Malformed(what) => write!(f, "malformed {}", what),
+        }
+    }
+}
+
+/// The kinds of events plugins can subscribe to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    UserJoined,
+    UserChanged,
+    ChannelCreated,
+    ChannelRenamed,
+    ChannelJoined,
+    MessageChanged,
+    MessageDeleted,
+}
+
+impl Event {
+    /// The kind of the event, if plugins can subscribe to it
+    pub fn kind(&self) -> Option<EventKind> {
+        match *self {
+            Event::UserJoined(_) => Some(EventKind::UserJoined),
+            Event::UserChanged(_) => Some(EventKind::UserChanged),
+            Event::ChannelCreated(_) => Some(EventKind::ChannelCreated),
+            Event::ChannelRenamed(_) => Some(EventKind::ChannelRenamed),
+            Event::ChannelJoined(_) => Some(EventKind::ChannelJoined),
+            Event::MessageChanged { .. } => Some(EventKind::MessageChanged),
+            Event::MessageDeleted { .. } => Some(EventKind::MessageDeleted),
+            Event::Message { .. } |
+            Event::Dropped(_) |
+            Event::Heartbeat => None,
+        }
+    }
+
+    /// The ID of the channel the event concerns, if any
+    pub fn channel(&self) -> Option<&str> {
+        match *self {
+            Event::Message { ref channel, .. } |
+            Event::MessageChanged { ref channel, .. } |
+            Event::MessageDeleted { ref channel, .. } => Some(channel),
+            Event::ChannelCreated(ref channel) |
+            Event::ChannelRenamed(ref channel) |
+            Event::ChannelJoined(ref channel) => Some(&channel.id),
+            Event::UserJoined(_) |
+            Event::UserChanged(_) |
+            Event::Dropped(_) |
+            Event::Heartbeat => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackendError(pub String);
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Receives events from a running backend
+pub trait EventHandler {
+    fn on_event(&mut self, backend: &ChatBackend, event: Event);
+    fn on_connect(&mut self, backend: &ChatBackend);
+    fn on_close(&mut self, backend: &ChatBackend);
+}
+
+/// A chat transport the bot can be connected to
+pub trait ChatBackend: Send + Sync {
+    /// Connects and passes incoming events to the handler until the connection is closed
+    fn run(&self, handler: &mut EventHandler) -> Result<(), BackendError>;
+    /// How long to wait before running again after `run` returns, or `None` to stop
+    fn reconnect_delay(&self) -> Option<Duration>;
+    fn send_message(&self, channel: &str, msg: &str) -> Result<(), BackendError>;
+    fn send_direct(&self, user: &str, msg: &str) -> Result<(), BackendError>;
+    fn send_in_thread(&self, channel: &str, thread_ts: &str, msg: &str) -> Result<(), BackendError>;
+    fn add_reaction(&self, channel: &str, ts: &str, emoji: &str) -> Result<(), BackendError>;
+    fn self_user(&self) -> Option<User>;
+    fn users(&self) -> Vec<User>;
+    /// The channels, private conversations and direct message channels the bot is in
+    fn channels(&self) -> Vec<Channel>;
+    /// The kind of a channel by its ID, also for channels opened after connecting
+    fn channel_kind(&self, id: &str) -> ChannelKind;
+}
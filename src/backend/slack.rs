@@ -0,0 +1,352 @@
+use super::{BackendError, Channel, ChannelKind, ChatBackend, DropReason, Event, EventHandler,
+            User};
+use chrono::Duration;
+use slack::{self, Message, RtmClient, Sender};
+use slack_api;
+use slack_api::{chat, im, reactions, MessageBotMessage, MessageMessageChanged,
+                MessageMessageDeleted, MessageStandard};
+use slack_api::requests::{self, Client};
+use slack_api::rtm::StartResponse;
+use std::sync::Mutex;
+use std::time;
+use timer::Timer;
+
+/// How often to ping Slack, so that heartbeats keep coming on a quiet connection
+const PING_INTERVAL: i64 = 30;
+/// Seconds to wait before reconnecting
+const RECONNECT_DELAY: u64 = 10;
+
+pub struct SlackBackend {
+    token: String,
+    sender: Mutex<Option<Sender>>,
+    start_response: Mutex<Option<StartResponse>>,
+}
+
+impl SlackBackend {
+    pub fn new(token: String) -> SlackBackend {
+        SlackBackend {
+            token: token,
+            sender: Mutex::new(None),
+            start_response: Mutex::new(None),
+        }
+    }
+
+    fn web_client(&self) -> Result<Client, BackendError> {
+        requests::default_client()
+            .map_err(|e| BackendError(format!("Couldn't create a web API client: {:?}", e)))
+    }
+
+    /// Finds the ID of the direct message channel with the given user, opening one if needed
+    fn direct_channel(&self, user: &str) -> Result<String, BackendError> {
+        let known = self.start_response
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|resp| resp.ims.as_ref())
+            .and_then(|ims| {
+                          ims.iter()
+                              .find(|im| im.user.as_ref().map(|u| u.as_str()) == Some(user))
+                              .and_then(|im| im.id.clone())
+                      });
+        if let Some(id) = known {
+            return Ok(id);
+        }
+
+        let client = self.web_client()?;
+        let request = im::OpenRequest {
+            user: user,
+            ..Default::default()
+        };
+        im::open(&client, &self.token, &request)
+            .map_err(|e| BackendError(format!("Couldn't open a direct channel: {:?}", e)))?
+            .channel
+            .and_then(|c| c.id)
+            .ok_or_else(|| BackendError("Slack didn't return a direct channel".to_string()))
+    }
+}
+
+fn convert_user(user: &slack_api::User) -> Option<User> {
+    Some(User {
+             id: user.id.clone()?,
+             name: user.name.clone()?,
+             is_primary_owner: user.is_primary_owner == Some(true),
+             is_owner: user.is_owner == Some(true),
+             is_admin: user.is_admin == Some(true),
+         })
+}
+
+fn convert_channel(channel: &slack_api::Channel) -> Option<Channel> {
+    Some(Channel {
+             id: channel.id.clone()?,
+             name: channel.name.clone()?,
+             kind: ChannelKind::Public,
+         })
+}
+
+fn convert_group(group: &slack_api::Group) -> Option<Channel> {
+    Some(Channel {
+             id: group.id.clone()?,
+             name: group.name.clone()?,
+             kind: ChannelKind::Private,
+         })
+}
+
+/// Names a direct message channel after the other user, or their ID if they're unknown
+fn convert_im(im: &slack_api::Im, users: &[slack_api::User]) -> Option<Channel> {
+    let user = im.user.as_ref()?;
+    let name = users
+        .iter()
+        .find(|u| u.id.as_ref() == Some(user))
+        .and_then(|u| u.name.clone())
+        .unwrap_or_else(|| user.clone());
+    Some(Channel {
+             id: im.id.clone()?,
+             name: name,
+             kind: ChannelKind::Direct,
+         })
+}
+
+fn require<T>(value: Option<T>, what: &'static str) -> Result<T, DropReason> {
+    value.ok_or(DropReason::Malformed(what))
+}
+
+/// Returns `None` for message subtypes the bot has no use for
+fn convert_message(msg: Message) -> Result<Option<Event>, DropReason> {
+    match msg {
+        Message::Standard(MessageStandard {
+                              user,
+                              text,
+                              channel,
+                              ts,
+                              thread_ts,
+                              bot_id,
+                              attachments,
+                              ..
+                          }) => {
+            if bot_id.is_some() {
+                return Err(DropReason::BotMessage(bot_id));
+            }
+            let user = user.ok_or(DropReason::NoUser)?;
+            let text = match text {
+                Some(ref text) if !text.is_empty() => text.clone(),
+                _ if attachments.map_or(false, |a| !a.is_empty()) => {
+                    return Err(DropReason::AttachmentsOnly)
+                }
+                _ => return Err(DropReason::Malformed("message without text")),
+            };
+            Ok(Some(Event::Message {
+                        user: user,
+                        channel: require(channel, "message without a channel")?,
+                        text: text,
+                        ts: require(ts, "message without a timestamp")?,
+                        thread_ts: thread_ts,
+                    }))
+        }
+        Message::BotMessage(MessageBotMessage { username, bot_id, .. }) => {
+            Err(DropReason::BotMessage(username.or(bot_id)))
+        }
+        Message::MessageChanged(MessageMessageChanged { channel, message, .. }) => {
+            let message = require(message, "edit without a message")?;
+            Ok(Some(Event::MessageChanged {
+                        channel: require(channel, "edit without a channel")?,
+                        ts: require(message.ts, "edit without a timestamp")?,
+                        user: message.user,
+                        text: message.text,
+                    }))
+        }
+        Message::MessageDeleted(MessageMessageDeleted {
+                                    channel,
+                                    deleted_ts,
+                                    ..
+                                }) => {
+            Ok(Some(Event::MessageDeleted {
+                        channel: require(channel, "deletion without a channel")?,
+                        ts: require(deleted_ts, "deletion without a timestamp")?,
+                    }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn convert_event(event: slack::Event) -> Option<Event> {
+    let user_event = |user: &slack_api::User, event: fn(User) -> Event| {
+        convert_user(user)
+            .map(event)
+            .unwrap_or(Event::Dropped(DropReason::Malformed("user")))
+    };
+    let channel_event = |channel: &slack_api::Channel, event: fn(Channel) -> Event| {
+        convert_channel(channel)
+            .map(event)
+            .unwrap_or(Event::Dropped(DropReason::Malformed("channel")))
+    };
+
+    match event {
+        slack::Event::Message(msg) => {
+            convert_message(*msg).unwrap_or_else(|reason| Some(Event::Dropped(reason)))
+        }
+        slack::Event::TeamJoin { user } => Some(user_event(&user, Event::UserJoined)),
+        slack::Event::UserChange { user } => Some(user_event(&user, Event::UserChanged)),
+        slack::Event::ChannelCreated { channel } => {
+            Some(channel_event(&channel, Event::ChannelCreated))
+        }
+        slack::Event::ChannelRename { channel } => {
+            Some(channel_event(&channel, Event::ChannelRenamed))
+        }
+        slack::Event::ChannelJoined { channel } => {
+            Some(channel_event(&channel, Event::ChannelJoined))
+        }
+        slack::Event::Pong { .. } => Some(Event::Heartbeat),
+        _ => None,
+    }
+}
+
+/// Passes events from the RTM client on to a backend-agnostic handler
+struct RtmAdapter<'a> {
+    backend: &'a SlackBackend,
+    handler: &'a mut EventHandler,
+}
+
+impl<'a> slack::EventHandler for RtmAdapter<'a> {
+    fn on_event(&mut self, _: &RtmClient, event: slack::Event) {
+        if let Some(event) = convert_event(event) {
+            self.handler.on_event(self.backend, event);
+        }
+    }
+
+    fn on_close(&mut self, _: &RtmClient) {
+        self.handler.on_close(self.backend);
+    }
+
+    fn on_connect(&mut self, _: &RtmClient) {
+        self.handler.on_connect(self.backend);
+    }
+}
+
+impl ChatBackend for SlackBackend {
+    fn run(&self, handler: &mut EventHandler) -> Result<(), BackendError> {
+        let client = RtmClient::login(&self.token)
+            .map_err(|e| BackendError(format!("Couldn't log in to Slack: {:?}", e)))?;
+        *self.sender.lock().unwrap() = Some(client.sender().clone());
+        *self.start_response.lock().unwrap() = Some(client.start_response().clone());
+
+        let ping_timer = Timer::new();
+        let _ping_guard = {
+            let sender = client.sender().clone();
+            ping_timer.schedule_repeating(Duration::seconds(PING_INTERVAL), move || {
+                let ping = format!(r#"{{"id": {}, "type": "ping"}}"#, sender.get_msg_uid());
+                let _ = sender.send(&ping);
+            })
+        };
+
+        let mut adapter = RtmAdapter {
+            backend: self,
+            handler: handler,
+        };
+        client
+            .run(&mut adapter)
+            .map_err(|e| BackendError(format!("{:?}", e)))
+    }
+
+    fn reconnect_delay(&self) -> Option<time::Duration> {
+        Some(time::Duration::from_secs(RECONNECT_DELAY))
+    }
+
+    fn send_message(&self, channel: &str, msg: &str) -> Result<(), BackendError> {
+        let sender = self.sender.lock().unwrap();
+        if let Some(ref sender) = *sender {
+            sender
+                .send_message(channel, msg)
+                .map(|_| ())
+                .map_err(|e| BackendError(format!("{:?}", e)))
+        } else {
+            Err(BackendError("Not connected".to_string()))
+        }
+    }
+
+    fn send_direct(&self, user: &str, msg: &str) -> Result<(), BackendError> {
+        let channel = self.direct_channel(user)?;
+        self.send_message(&channel, msg)
+    }
+
+    fn send_in_thread(&self,
+                      channel: &str,
+                      thread_ts: &str,
+                      msg: &str)
+                      -> Result<(), BackendError> {
+        let client = self.web_client()?;
+        let request = chat::PostMessageRequest {
+            channel: channel,
+            text: msg,
+            as_user: Some(true),
+            thread_ts: Some(thread_ts),
+            ..Default::default()
+        };
+        chat::post_message(&client, &self.token, &request)
+            .map(|_| ())
+            .map_err(|e| BackendError(format!("{:?}", e)))
+    }
+
+    fn add_reaction(&self, channel: &str, ts: &str, emoji: &str) -> Result<(), BackendError> {
+        let client = self.web_client()?;
+        let request = reactions::AddRequest {
+            name: emoji.trim_matches(':'),
+            channel: Some(channel),
+            timestamp: Some(ts),
+            ..Default::default()
+        };
+        reactions::add(&client, &self.token, &request)
+            .map(|_| ())
+            .map_err(|e| BackendError(format!("{:?}", e)))
+    }
+
+    fn self_user(&self) -> Option<User> {
+        self.start_response
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|resp| resp.slf.as_ref())
+            .and_then(convert_user)
+    }
+
+    fn users(&self) -> Vec<User> {
+        self.start_response
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|resp| resp.users.as_ref())
+            .map(|users| users.iter().filter_map(convert_user).collect())
+            .unwrap_or_default()
+    }
+
+    fn channels(&self) -> Vec<Channel> {
+        let start_response = self.start_response.lock().unwrap();
+        let resp = match *start_response {
+            Some(ref resp) => resp,
+            None => return Vec::new(),
+        };
+        let mut channels = Vec::new();
+        if let Some(ref public) = resp.channels {
+            channels.extend(public.iter().filter_map(convert_channel));
+        }
+        if let Some(ref groups) = resp.groups {
+            channels.extend(groups.iter().filter_map(convert_group));
+        }
+        if let Some(ref ims) = resp.ims {
+            let users = resp.users.as_ref().map_or(&[][..], |users| &users[..]);
+            channels.extend(ims.iter().filter_map(|im| convert_im(im, users)));
+        }
+        channels
+    }
+
+    fn channel_kind(&self, id: &str) -> ChannelKind {
+        if let Some(channel) = self.channels().into_iter().find(|channel| channel.id == id) {
+            return channel.kind;
+        }
+        // channels opened since connecting can still be told apart by their IDs
+        match id.chars().next() {
+            Some('D') => ChannelKind::Direct,
+            Some('G') => ChannelKind::Private,
+            _ => ChannelKind::Public,
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use settings::{SETTINGS, Settings};
+
+/// Normalizes a channel given as `name`, `#name` or a Slack channel link `<#ID|name>`
+pub fn normalize_channel(channel: &str) -> String {
+    let channel = channel.trim();
+    let channel = if channel.starts_with("<#") && channel.ends_with('>') {
+        let inner = &channel[2..channel.len() - 1];
+        inner.rsplit('|').next().unwrap_or(inner)
+    } else {
+        channel.trim_left_matches('#')
+    };
+    channel.to_lowercase()
+}
+
+fn normalize_list(channels: &[String]) -> Vec<String> {
+    channels
+        .iter()
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .map(normalize_channel)
+        .collect()
+}
+
+/// Per-plugin lists of channels the plugin is allowed or denied to run in.
+///
+/// A channel on the deny list is always disabled. If the allow list is not
+/// empty, only channels on it are enabled.
+pub struct ChannelFilter {
+    plugin: String,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl ChannelFilter {
+    pub fn load(plugin: &str) -> ChannelFilter {
+        ChannelFilter::from_settings(plugin, &SETTINGS.lock().unwrap())
+    }
+
+    pub fn from_settings(plugin: &str, settings: &Settings) -> ChannelFilter {
+        let settings = settings.plugin(plugin);
+        ChannelFilter {
+            plugin: plugin.to_string(),
+            allow: normalize_list(&settings.channels_allow),
+            deny: normalize_list(&settings.channels_deny),
+        }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let mut settings = SETTINGS.lock().unwrap();
+        {
+            let plugin = settings.plugin_mut(&self.plugin);
+            plugin.channels_allow = self.allow.clone();
+            plugin.channels_deny = self.deny.clone();
+        }
+        settings
+            .save()
+            .map_err(|e| format!("Couldn't save the settings: {}", e))
+    }
+
+    /// Checks the channel by its ID and its name
+    pub fn is_enabled(&self, channel_id: &str, channel_name: &str) -> bool {
+        let id = channel_id.to_lowercase();
+        let name = normalize_channel(channel_name);
+        let listed = |list: &Vec<String>| list.iter().any(|c| *c == id || *c == name);
+
+        if listed(&self.deny) {
+            false
+        } else {
+            self.allow.is_empty() || listed(&self.allow)
+        }
+    }
+
+    pub fn allow(&mut self, channel: &str) -> Result<(), String> {
+        let channel = normalize_channel(channel);
+        self.deny.retain(|c| *c != channel);
+        if !self.allow.contains(&channel) {
+            self.allow.push(channel);
+        }
+        self.save()
+    }
+
+    pub fn deny(&mut self, channel: &str) -> Result<(), String> {
+        let channel = normalize_channel(channel);
+        self.allow.retain(|c| *c != channel);
+        if !self.deny.contains(&channel) {
+            self.deny.push(channel);
+        }
+        self.save()
+    }
+
+    /// Removes the channel from both lists, or clears the lists if no channel is given
+    pub fn reset(&mut self, channel: Option<&str>) -> Result<(), String> {
+        if let Some(channel) = channel {
+            let channel = normalize_channel(channel);
+            self.allow.retain(|c| *c != channel);
+            self.deny.retain(|c| *c != channel);
+        } else {
+            self.allow.clear();
+            self.deny.clear();
+        }
+        self.save()
+    }
+
+    pub fn describe(&self) -> String {
+        let list = |list: &Vec<String>| if list.is_empty() {
+            "-".to_string()
+        } else {
+            list.iter()
+                .map(|c| format!("#{}", c))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        format!("{}: allow {}, deny {}",
+                self.plugin,
+                list(&self.allow),
+                list(&self.deny))
+    }
+}
@@ -0,0 +1,438 @@
+use permissions::Role;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone)]
+pub enum ArgSpec {
+    Required(&'static str),
+    Optional(&'static str),
+    /// Any number of remaining arguments
+    Rest(&'static str),
+}
+
+impl fmt::Display for ArgSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArgSpec::Required(name) => write!(f, "<{}>", name),
+            ArgSpec::Optional(name) => write!(f, "[{}]", name),
+            ArgSpec::Rest(name) => write!(f, "[{}...]", name),
+        }
+    }
+}
+
+/// Describes a command a plugin responds to
+#[derive(Clone)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: Vec<&'static str>,
+    pub args: Vec<ArgSpec>,
+    pub help: &'static str,
+    /// The lowest role allowed to use the command
+    pub role: Role,
+}
+
+impl CommandSpec {
+    pub fn new(name: &'static str, help: &'static str) -> CommandSpec {
+        CommandSpec {
+            name: name,
+            aliases: Vec::new(),
+            args: Vec::new(),
+            help: help,
+            role: Role::User,
+        }
+    }
+
+    pub fn role(mut self, role: Role) -> CommandSpec {
+        self.role = role;
+        self
+    }
+
+    pub fn alias(mut self, alias: &'static str) -> CommandSpec {
+        self.aliases.push(alias);
+        self
+    }
+
+    pub fn arg(mut self, arg: ArgSpec) -> CommandSpec {
+        self.args.push(arg);
+        self
+    }
+
+    pub fn usage(&self, command_char: &str) -> String {
+        let mut result = format!("{}{}", command_char, self.name);
+        for arg in &self.args {
+            result.push_str(&format!(" {}", arg));
+        }
+        result
+    }
+
+    fn accepts(&self, num_args: usize) -> bool {
+        let mut min = 0;
+        let mut max = Some(0);
+        for arg in &self.args {
+            match *arg {
+                ArgSpec::Required(_) => {
+                    min += 1;
+                    max = max.map(|m| m + 1);
+                }
+                ArgSpec::Optional(_) => {
+                    max = max.map(|m| m + 1);
+                }
+                ArgSpec::Rest(_) => {
+                    max = None;
+                }
+            }
+        }
+        num_args >= min && max.map_or(true, |m| num_args <= m)
+    }
+}
+
+/// A parsed command invocation
+pub struct Command {
+    /// The canonical name of the command, even if it was invoked by an alias
+    pub name: String,
+    pub args: Vec<String>,
+    /// The text following the command name, exactly as sent
+    pub raw: String,
+}
+
+impl Command {
+    /// The text after the first `count` arguments, exactly as sent
+    pub fn raw_after(&self, count: usize) -> &str {
+        if count == 0 {
+            return &self.raw;
+        }
+        // the same splitting as `split_args`
+        let mut skipped = 0;
+        let mut in_arg = false;
+        let mut in_quotes = false;
+        let mut escaped = false;
+        for (i, c) in self.raw.char_indices() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\\' => {
+                    in_arg = true;
+                    escaped = true;
+                }
+                '"' => {
+                    in_arg = true;
+                    in_quotes = !in_quotes;
+                }
+                c if c.is_whitespace() && !in_quotes => {
+                    if in_arg {
+                        skipped += 1;
+                        in_arg = false;
+                        if skipped == count {
+                            return self.raw[i..].trim_left();
+                        }
+                    }
+                }
+                _ => in_arg = true,
+            }
+        }
+        ""
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CommandOwner {
+    Core,
+    Plugin(usize),
+}
+
+pub enum CommandError {
+    /// The message contained only the command character
+    Empty,
+    UnknownCommand(String),
+    UnterminatedQuote,
+    /// Wrong number of arguments; contains the usage string
+    Usage(String),
+    /// The user, the command and the role it requires
+    NotAllowed(String, String, Role),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Empty => write!(f, "No command given"),
+            CommandError::UnknownCommand(ref name) => write!(f, "Unknown command: {}", name),
+            CommandError::UnterminatedQuote => write!(f, "Unterminated quote"),
+            CommandError::Usage(ref usage) => write!(f, "Usage: {}", usage),
+            CommandError::NotAllowed(ref user, ref command, role) => {
+                write!(f, "{}: {} requires the {} role", user, command, role)
+            }
+        }
+    }
+}
+
+/// Splits the text into whitespace-separated arguments.
+///
+/// Text in double quotes is kept as a single argument; a backslash escapes
+/// the following character.
+pub fn split_args(text: &str) -> Result<Vec<String>, CommandError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut in_quotes = false;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                in_arg = true;
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            '"' => {
+                in_arg = true;
+                in_quotes = !in_quotes;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if in_arg {
+                    args.push(current);
+                    current = String::new();
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+
+    if in_quotes {
+        return Err(CommandError::UnterminatedQuote);
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// The number of characters to insert, delete or replace to turn one text into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let replaced = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub struct CommandRegistry {
+    commands: Vec<(CommandOwner, CommandSpec)>,
+    names: HashMap<String, usize>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: Vec::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    /// Registers a command; returns the names that were already taken and have been skipped
+    pub fn register(&mut self, owner: CommandOwner, spec: CommandSpec) -> Vec<&'static str> {
+        let index = self.commands.len();
+        let mut conflicts = Vec::new();
+        for name in Some(&spec.name).into_iter().chain(spec.aliases.iter()) {
+            if self.names.contains_key(*name) {
+                conflicts.push(*name);
+            } else {
+                self.names.insert(name.to_string(), index);
+            }
+        }
+        self.commands.push((owner, spec));
+        conflicts
+    }
+
+    pub fn find(&self, name: &str) -> Option<&(CommandOwner, CommandSpec)> {
+        self.names
+            .get(&name.to_lowercase())
+            .map(|&index| &self.commands[index])
+    }
+
+    /// The registered name closest to an unknown one, if it is likely a typo of it
+    pub fn suggest(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        if name.chars().count() < 3 {
+            return None;
+        }
+        // a single typo in short names, two in longer ones
+        let max_distance = if name.chars().count() < 5 { 1 } else { 2 };
+        self.names
+            .keys()
+            .map(|known| (edit_distance(&name, known), known))
+            .filter(|&(distance, known)| distance <= max_distance && known.len() >= 3)
+            .min()
+            .map(|(_, known)| known.as_str())
+    }
+
+    pub fn specs(&self) -> Vec<&CommandSpec> {
+        let mut specs: Vec<_> = self.commands.iter().map(|&(_, ref spec)| spec).collect();
+        specs.sort_by_key(|spec| spec.name);
+        specs
+    }
+
+    /// Parses the text following the command character
+    pub fn parse(&self,
+                 text: &str,
+                 command_char: &str)
+                 -> Result<(CommandOwner, Command), CommandError> {
+        let text = text.trim_left();
+        let name_end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let (name, raw) = text.split_at(name_end);
+        if name.is_empty() {
+            return Err(CommandError::Empty);
+        }
+
+        let &(owner, ref spec) = self.find(name)
+            .ok_or_else(|| CommandError::UnknownCommand(name.to_string()))?;
+        let raw = raw.trim_left();
+        let args = split_args(raw)?;
+        if !spec.accepts(args.len()) {
+            return Err(CommandError::Usage(spec.usage(command_char)));
+        }
+
+        Ok((owner,
+            Command {
+                name: spec.name.to_string(),
+                args: args,
+                raw: raw.to_string(),
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        registry.register(CommandOwner::Core,
+                          CommandSpec::new("help", "").arg(ArgSpec::Optional("command")));
+        registry.register(CommandOwner::Plugin(0),
+                          CommandSpec::new("remind", "")
+                              .alias("r")
+                              .arg(ArgSpec::Required("when"))
+                              .arg(ArgSpec::Rest("text")));
+        registry
+    }
+
+    fn split(text: &str) -> Vec<String> {
+        match split_args(text) {
+            Ok(args) => args,
+            Err(e) => panic!("{} failed: {}", text, e),
+        }
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(split("  a  b\tc "), vec!["a", "b", "c"]);
+        assert!(split("").is_empty());
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn keeps_quoted_text_together() {
+        assert_eq!(split("say \"hello  world\" now"), vec!["say", "hello  world", "now"]);
+        assert_eq!(split("a\"b c\"d"), vec!["ab cd"]);
+        assert_eq!(split("\"\""), vec![""]);
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(split("\\\"a b"), vec!["\"a", "b"]);
+        assert_eq!(split("a\\ b"), vec!["a b"]);
+        assert_eq!(split("end\\"), vec!["end"]);
+    }
+
+    #[test]
+    fn rejects_unbalanced_quotes() {
+        match split_args("say \"hello") {
+            Err(CommandError::UnterminatedQuote) => {}
+            _ => panic!("expected an unterminated quote"),
+        }
+        match registry().parse("remind \"now", "!") {
+            Err(CommandError::UnterminatedQuote) => {}
+            _ => panic!("expected an unterminated quote"),
+        }
+    }
+
+    #[test]
+    fn bare_command_char_is_empty() {
+        for text in &["", "   "] {
+            match registry().parse(text, "!") {
+                Err(CommandError::Empty) => {}
+                _ => panic!("expected an empty command for {:?}", text),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_aliases_and_keeps_raw_text() {
+        let (owner, command) = match registry().parse("R  5m  \"take  a\" break", "!") {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(owner == CommandOwner::Plugin(0));
+        assert_eq!(command.name, "remind");
+        assert_eq!(command.args, vec!["5m", "take  a", "break"]);
+        assert_eq!(command.raw, "5m  \"take  a\" break");
+    }
+
+    #[test]
+    fn takes_the_raw_text_after_arguments() {
+        let (_, command) = match registry().parse("remind \"in 5m\"  say \"hi\"  there ",
+                                                    "!") {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(command.raw_after(0), "\"in 5m\"  say \"hi\"  there ");
+        assert_eq!(command.raw_after(1), "say \"hi\"  there ");
+        assert_eq!(command.raw_after(2), "\"hi\"  there ");
+        assert_eq!(command.raw_after(4), "");
+    }
+
+    #[test]
+    fn checks_the_number_of_arguments() {
+        match registry().parse("remind", "!") {
+            Err(CommandError::Usage(usage)) => assert_eq!(usage, "!remind <when> [text...]"),
+            _ => panic!("expected the usage"),
+        }
+        match registry().parse("help a b", "!") {
+            Err(CommandError::Usage(_)) => {}
+            _ => panic!("expected the usage"),
+        }
+    }
+
+    #[test]
+    fn reports_unknown_commands() {
+        match registry().parse("!!", "!") {
+            Err(CommandError::UnknownCommand(name)) => assert_eq!(name, "!!"),
+            _ => panic!("expected an unknown command"),
+        }
+    }
+
+    #[test]
+    fn suggests_only_close_names() {
+        let registry = registry();
+        assert_eq!(registry.suggest("hepl"), None);
+        assert_eq!(registry.suggest("halp"), Some("help"));
+        assert_eq!(registry.suggest("remnid"), Some("remind"));
+        assert_eq!(registry.suggest("important"), None);
+        assert_eq!(registry.suggest("!!"), None);
+        assert_eq!(registry.suggest("x"), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Which of the imported messages are learned from.
+
+use chrono::NaiveDate;
+use format::Message;
+
+/// Channels are compared without the `#` and the case, as the logs don't agree on them
+pub fn channel_key(channel: &str) -> String {
+    channel.trim_left_matches('#').to_lowercase()
+}
+
+/// Nicks are compared without the role prefix and the case
+pub fn nick_key(nick: &str) -> String {
+    nick.trim_left_matches(|c| "&~@%+ ".contains(c)).to_lowercase()
+}
+
+pub struct Filter {
+    /// Only these users' messages are learned from, unless it is empty
+    pub nicks: Vec<String>,
+    pub exclude_nicks: Vec<String>,
+    /// Users whose nick contains one of these aren't learned from either
+    pub exclude_nicks_containing: Vec<String>,
+    /// Only these channels are learned from, unless it is empty
+    pub channels: Vec<String>,
+    pub exclude_channels: Vec<String>,
+    /// Messages starting with these are commands to bots
+    pub command_prefixes: Vec<String>,
+    /// Messages of unknown date are skipped when either end of the range is set
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl Filter {
+    pub fn wants_channel(&self, channel: Option<&str>) -> bool {
+        let channel = channel.map(channel_key);
+        match channel {
+            Some(ref channel) if self.exclude_channels.contains(channel) => false,
+            Some(ref channel) => self.channels.is_empty() || self.channels.contains(channel),
+            None => self.channels.is_empty(),
+        }
+    }
+
+    fn wants_nick(&self, nick: &str) -> bool {
+        let nick = nick_key(nick);
+        !self.exclude_nicks.contains(&nick) &&
+        !self.exclude_nicks_containing
+             .iter()
+             .any(|part| nick.contains(part.as_str())) &&
+        (self.nicks.is_empty() || self.nicks.contains(&nick))
+    }
+
+    fn wants_date(&self, date: Option<NaiveDate>) -> bool {
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        date.map_or(false, |date| {
+            self.from.map_or(true, |from| date >= from) && self.to.map_or(true, |to| date <= to)
+        })
+    }
+
+    pub fn wants(&self, message: &Message) -> bool {
+        !message.bot && self.wants_nick(message.nick) &&
+        !self.command_prefixes
+             .iter()
+             .any(|prefix| message.text.starts_with(prefix.as_str())) &&
+        self.wants_channel(message.channel) && self.wants_date(message.date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use format::parse_date;
+
+    fn filter() -> Filter {
+        Filter {
+            nicks: Vec::new(),
+            exclude_nicks: Vec::new(),
+            exclude_nicks_containing: vec!["lucidbot".to_string()],
+            channels: Vec::new(),
+            exclude_channels: Vec::new(),
+            command_prefixes: vec!["!".to_string()],
+            from: None,
+            to: None,
+        }
+    }
+
+    fn message<'a>(channel: Option<&'a str>, nick: &'a str, text: &'a str) -> Message<'a> {
+        Message {
+            channel: channel,
+            nick: nick,
+            text: text,
+            date: parse_date("2017-01-31"),
+            bot: false,
+        }
+    }
+
+    #[test]
+    fn skips_the_bots_and_their_commands() {
+        let filter = filter();
+        assert!(filter.wants(&message(Some("general"), "@alice", "hello")));
+        assert!(!filter.wants(&message(Some("general"), "alice", "!grep hello")));
+        for nick in &["lucidbot", "@LucidBot", "lucidbot2", "old_lucidbot"] {
+            assert!(!filter.wants(&message(Some("general"), nick, "hello")), "{}", nick);
+        }
+        let mut from_bot = message(Some("general"), "helper", "hello");
+        from_bot.bot = true;
+        assert!(!filter.wants(&from_bot));
+    }
+
+    #[test]
+    fn picks_the_nicks_and_channels() {
+        let mut filter = filter();
+        filter.nicks = vec![nick_key("~Alice"), nick_key("bob")];
+        filter.exclude_nicks = vec![nick_key("bob")];
+        filter.exclude_channels = vec![channel_key("#Random")];
+        assert!(filter.wants(&message(Some("#general"), "&alice", "hi")));
+        assert!(!filter.wants(&message(Some("general"), "bob", "hi")));
+        assert!(!filter.wants(&message(Some("general"), "carol", "hi")));
+        assert!(!filter.wants(&message(Some("random"), "alice", "hi")));
+        assert!(filter.wants(&message(None, "alice", "hi")));
+
+        filter.channels = vec![channel_key("general")];
+        assert!(filter.wants(&message(Some("General"), "alice", "hi")));
+        assert!(!filter.wants(&message(Some("dev"), "alice", "hi")));
+        assert!(!filter.wants(&message(None, "alice", "hi")));
+    }
+
+    #[test]
+    fn keeps_the_dates_in_the_range() {
+        let mut filter = filter();
+        filter.from = parse_date("2017-01-01");
+        filter.to = parse_date("2017-01-31");
+        let mut line = message(Some("general"), "alice", "hi");
+        assert!(filter.wants(&line));
+        line.date = parse_date("2017-02-01");
+        assert!(!filter.wants(&line));
+        line.date = None;
+        assert!(!filter.wants(&line));
+
+        filter.from = None;
+        filter.to = None;
+        assert!(filter.wants(&line));
+    }
+}
@@ -0,0 +1,342 @@
+//! The formats of the chat lines in the logs dict-gen learns from.
+
+use chrono::NaiveDate;
+use dictionary::logfile;
+use regex::Regex;
+use slack;
+use std::path::Path;
+
+/// The number of lines looked at to detect the format of a file
+const DETECT_LINES: usize = 100;
+
+/// The number of files an import read, and of those it couldn't
+#[derive(Default)]
+pub struct FileCount {
+    pub read: usize,
+    pub unreadable: usize,
+}
+
+/// A chat line of a log
+pub struct ChatLine<'a> {
+    /// Empty for formats which don't name the user
+    pub nick: &'a str,
+    pub message: &'a str,
+    /// `None` for formats which only have the time
+    pub date: Option<NaiveDate>,
+}
+
+/// A message to learn from, whatever it was imported from
+pub struct Message<'a> {
+    pub channel: Option<&'a str>,
+    pub nick: &'a str,
+    pub text: &'a str,
+    pub date: Option<NaiveDate>,
+    /// Whether the source marks the sender as a bot
+    pub bot: bool,
+}
+
+pub trait LineFormat {
+    fn name(&self) -> &'static str;
+
+    /// Parses a line; returns `None` for lines which aren't chat, like the status lines
+    fn parse<'a>(&self, line: &'a str) -> Option<ChatLine<'a>>;
+
+    /// The channel of a log, from its path under the base directory
+    fn channel(&self, base_dir: &Path, path: &Path) -> Option<String>;
+
+    /// Whether the format can be told from the lines; formats which accept any line can't
+    fn detectable(&self) -> bool {
+        true
+    }
+
+    /// Whether a channel of the logs is private, and isn't learned from unless asked for
+    fn is_private(&self, _: &str) -> bool {
+        false
+    }
+}
+
+/// A format of lines matched by a regex with `nick` and `message` groups, and a `date`
+/// group if the lines have the date
+pub struct RegexFormat {
+    name: &'static str,
+    regex: Regex,
+    channel: fn(&Path, &Path) -> Option<String>,
+    detectable: bool,
+    private: fn(&str) -> bool,
+}
+
+fn name_of(path: &Path) -> Option<String> {
+    path.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// The bot's logs are at `<channel>/YYYY/MM/DD.txt`, or `YYYY/MM/DD.txt` before they were
+/// split by channel
+fn bot_channel(base_dir: &Path, path: &Path) -> Option<String> {
+    let channel_dir = path.parent()?.parent()?.parent()?;
+    if channel_dir == base_dir {
+        None
+    } else {
+        name_of(channel_dir)
+    }
+}
+
+fn not_private(_: &str) -> bool {
+    false
+}
+
+/// Logs named after their channel, or named by date in a directory named after it
+fn file_channel(_: &Path, path: &Path) -> Option<String> {
+    let path = logfile::uncompressed_path(path);
+    let stem = path.file_stem()?.to_string_lossy().into_owned();
+    if stem.starts_with(|c: char| c.is_digit(10)) {
+        name_of(path.parent()?)
+    } else {
+        Some(stem)
+    }
+}
+
+/// Weechat logs are named like `irc.<server>.<channel>.weechatlog`
+fn weechat_channel(base_dir: &Path, path: &Path) -> Option<String> {
+    let name = file_channel(base_dir, path)?;
+    if name.starts_with("irc.") {
+        name.splitn(3, '.').nth(2).map(|channel| channel.to_string())
+    } else {
+        Some(name)
+    }
+}
+
+pub fn parse_date(text: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()
+}
+
+/// The date of a log named like `YYYY-MM-DD.log` or, as the bot's, `YYYY/MM/DD.txt`
+pub fn file_date(path: &Path) -> Option<NaiveDate> {
+    let path = logfile::uncompressed_path(path);
+    let stem = path.file_stem()?.to_string_lossy().into_owned();
+    if let Some(date) = parse_date(&stem) {
+        return Some(date);
+    }
+    let month_dir = path.parent()?;
+    let year_dir = month_dir.parent()?;
+    parse_date(&format!("{}-{}-{}",
+                        name_of(year_dir)?,
+                        name_of(month_dir)?,
+                        stem))
+}
+
+impl RegexFormat {
+    /// `[YYYY-MM-DD HH:MM:SS] <nick> message`, as written by the bot's logger
+    pub fn bot() -> RegexFormat {
+        RegexFormat {
+            name: "bot",
+            regex: Regex::new(concat!(r"^\[(?P<date>\d\d\d\d-\d\d-\d\d) \d\d:\d\d:\d\d\] ",
+                                      r"<(?P<nick>[^>]+)> ?(?P<message>.*)"))
+                    .unwrap(),
+            channel: bot_channel,
+            detectable: true,
+            private: logfile::is_private_stream,
+        }
+    }
+
+    /// `(YYYY-MM-DD HH:MM:SS) <nick> message`, as written by the old logger
+    pub fn legacy() -> RegexFormat {
+        RegexFormat {
+            name: "legacy",
+            regex: Regex::new(concat!(r"\((?P<date>\d\d\d\d-\d\d-\d\d) \d\d:\d\d:\d\d\)\s*",
+                                      r"<(?P<nick>[^>]+)> (?P<message>.*)"))
+                    .unwrap(),
+            channel: bot_channel,
+            detectable: true,
+            private: not_private,
+        }
+    }
+
+    /// `HH:MM <@nick> message`, irssi's default
+    pub fn irssi() -> RegexFormat {
+        RegexFormat {
+            name: "irssi",
+            regex: Regex::new(r"^\d\d:\d\d(:\d\d)? <[ @%+~&]?(?P<nick>[^>]+)> (?P<message>.*)")
+                .unwrap(),
+            channel: file_channel,
+            detectable: true,
+            private: not_private,
+        }
+    }
+
+    /// `YYYY-MM-DD HH:MM:SS<tab>@nick<tab>message`; the status lines have prefixes like
+    /// `-->` or ` *` instead of a nick
+    pub fn weechat() -> RegexFormat {
+        RegexFormat {
+            name: "weechat",
+            regex: Regex::new(concat!(r"^(?P<date>\d\d\d\d-\d\d-\d\d) \d\d:\d\d:\d\d\t[@%+~&]?",
+                                      r"(?P<nick>[^\t *<>=-][^\t]*)\t(?P<message>.*)"))
+                    .unwrap(),
+            channel: weechat_channel,
+            detectable: true,
+            private: not_private,
+        }
+    }
+
+    /// One message per line, without names
+    pub fn plain() -> RegexFormat {
+        RegexFormat {
+            name: "plain",
+            regex: Regex::new(r"^(?P<message>.*\S.*)$").unwrap(),
+            channel: file_channel,
+            detectable: false,
+            private: not_private,
+        }
+    }
+}
+
+impl LineFormat for RegexFormat {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn parse<'a>(&self, line: &'a str) -> Option<ChatLine<'a>> {
+        self.regex
+            .captures(line)
+            .map(|caps| {
+                     ChatLine {
+                         nick: caps.name("nick").unwrap_or(""),
+                         message: caps.name("message").unwrap(),
+                         date: caps.name("date").and_then(parse_date),
+                     }
+                 })
+    }
+
+    fn channel(&self, base_dir: &Path, path: &Path) -> Option<String> {
+        (self.channel)(base_dir, path)
+    }
+
+    fn detectable(&self) -> bool {
+        self.detectable
+    }
+
+    fn is_private(&self, channel: &str) -> bool {
+        (self.private)(channel)
+    }
+}
+
+/// All the formats, the default first
+pub fn formats() -> Vec<Box<LineFormat>> {
+    vec![Box::new(RegexFormat::bot()),
+         Box::new(RegexFormat::legacy()),
+         Box::new(RegexFormat::irssi()),
+         Box::new(RegexFormat::weechat()),
+         Box::new(RegexFormat::plain())]
+}
+
+/// The names accepted by `parse_choice`
+pub fn choice_names() -> String {
+    let mut names = vec!["auto"];
+    names.extend(formats().iter().map(|format| format.name()));
+    names.push(slack::NAME);
+    names.join(", ")
+}
+
+/// What the logs are read as
+pub enum Choice {
+    /// Detect a Slack export, or else the format of each file
+    Auto,
+    /// The line format of the given name
+    Format(&'static str),
+    SlackExport,
+}
+
+pub fn by_name(name: &str) -> Option<Box<LineFormat>> {
+    formats().into_iter().find(|format| format.name() == name)
+}
+
+pub fn parse_choice(name: &str) -> Result<Choice, String> {
+    match name {
+        "auto" => Ok(Choice::Auto),
+        name if name == slack::NAME => Ok(Choice::SlackExport),
+        _ => {
+            by_name(name)
+                .map(|format| Choice::Format(format.name()))
+                .ok_or_else(|| {
+                                format!("Unknown log format {}, expected one of: {}",
+                                        name,
+                                        choice_names())
+                            })
+        }
+    }
+}
+
+/// The detectable format which parses the most of the first lines of a log; the default
+/// one if none parses any
+pub fn detect<'f>(formats: &'f [Box<LineFormat>], contents: &str) -> &'f LineFormat {
+    let mut best = &*formats[0];
+    let mut best_count = 0;
+    for format in formats.iter().filter(|format| format.detectable()) {
+        let count = contents
+            .lines()
+            .take(DETECT_LINES)
+            .filter(|line| format.parse(line).is_some())
+            .count();
+        if count > best_count {
+            best = &**format;
+            best_count = count;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_lines_of_each_format() {
+        let date = parse_date("2017-01-31");
+        let cases = [("bot", "[2017-01-31 10:00:00] <&alice> hello there",
+                      Some(("&alice", "hello there", date))),
+                     ("bot", "[2017-01-31 10:00:00] <bob>", Some(("bob", "", date))),
+                     ("bot", "[2017-01-31 10:00:00] *** Connected ***", None),
+                     ("bot", "[2017-01-31 10:00:00] *** bob edited a message: hi", None),
+                     ("legacy", "(2017-01-31 10:00:00) <alice> hello",
+                      Some(("alice", "hello", date))),
+                     ("legacy", "(2017-01-31 10:00:00) *** alice joined", None),
+                     ("irssi", "10:00 <@alice> hello", Some(("alice", "hello", None))),
+                     ("irssi", "10:00:05 < bob> hi", Some(("bob", "hi", None))),
+                     ("irssi", "10:00 -!- alice has joined #general", None),
+                     ("weechat", "2017-01-31 10:00:00\t@alice\thello",
+                      Some(("alice", "hello", date))),
+                     ("weechat", "2017-01-31 10:00:00\t-->\talice has joined", None),
+                     ("weechat", "2017-01-31 10:00:00\t *\talice waves", None),
+                     ("plain", "just some words", Some(("", "just some words", None))),
+                     ("plain", "   ", None)];
+        for &(name, line, expected) in &cases {
+            let format = by_name(name).unwrap();
+            let parsed = format.parse(line).map(|chat| (chat.nick, chat.message, chat.date));
+            assert_eq!(parsed, expected, "{}: {}", name, line);
+        }
+    }
+
+    #[test]
+    fn detects_the_format_of_a_log() {
+        let formats = formats();
+        let cases = [("[2017-01-31 10:00:00] <alice> hi\n[2017-01-31 10:00:01] <bob> hey\n", "bot"),
+                     ("(2017-01-31 10:00:00) <alice> hi\n", "legacy"),
+                     ("--- Log opened\n10:00 <@alice> hi\n10:01 < bob> hey\n", "irssi"),
+                     ("2017-01-31 10:00:00\t@alice\thi\n", "weechat"),
+                     // the default, as plain text can't be told from anything else
+                     ("no format at all\nreally\n", "bot"),
+                     ("", "bot")];
+        for &(contents, name) in &cases {
+            assert_eq!(detect(&formats, contents).name(), name, "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn only_the_bot_logs_have_private_channels() {
+        let bot = RegexFormat::bot();
+        for name in &["@alice", "@#secret", "~system"] {
+            assert!(bot.is_private(name), "{}", name);
+        }
+        assert!(!bot.is_private("general"));
+        assert!(!RegexFormat::irssi().is_private("@alice"));
+    }
+}
@@ -0,0 +1,258 @@
+//! The `stats`, `generate` and `query` commands, looking into a dictionary.
+
+use dictionary::{Dictionary, Word};
+use getopts::{Matches, Options};
+use rand::{self, SeedableRng, StdRng};
+use std::collections::HashMap;
+
+const DEFAULT_DICTIONARY: &'static str = "dictionary.dat";
+const DEFAULT_TOP: usize = 10;
+
+/// How the sentence markers are written in `query`
+const START: &'static str = "^";
+const END: &'static str = "$";
+
+/// Adds the options shared by the commands, parses the arguments after the name and loads
+/// the dictionary; `None` if only the help was asked for
+fn parse(program: &str,
+         usage: &str,
+         mut opts: Options,
+         args: &[String])
+         -> Result<Option<(Matches, Dictionary)>, String> {
+    opts.optopt("d",
+                "dictionary",
+                &format!("the dictionary to read, {} by default", DEFAULT_DICTIONARY),
+                "FILE");
+    opts.optflag("h", "help", "print this help");
+    let matches = opts.parse(args).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(&format!("Usage: {} {}", program, usage)));
+        return Ok(None);
+    }
+    let path = matches.opt_str("d").unwrap_or(DEFAULT_DICTIONARY.into());
+    let dict = Dictionary::load(&path)
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    Ok(Some((matches, dict)))
+}
+
+fn number_opt(matches: &Matches, name: &str, default: usize) -> Result<usize, String> {
+    match matches.opt_str(name) {
+        Some(text) => text.parse().map_err(|_| format!("Invalid number {}", text)),
+        None => Ok(default),
+    }
+}
+
+fn word_name(dict: &Dictionary, word: Word) -> &str {
+    match word {
+        Word::Start1 | Word::Start2 => START,
+        Word::End => END,
+        Word::Word(_) => dict.word_text(word).unwrap_or("?"),
+    }
+}
+
+/// The `count` largest values, largest first
+fn top<K: Copy + Ord>(counts: HashMap<K, u64>, count: usize) -> Vec<(K, u64)> {
+    let mut counts: Vec<(K, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts.truncate(count);
+    counts
+}
+
+/// The uses of the dictionary: the number of transitions, and the uses of each word and
+/// of each pair of words
+struct Uses {
+    transitions: u64,
+    words: HashMap<Word, u64>,
+    bigrams: HashMap<(Word, Word), u64>,
+}
+
+fn count_uses(dict: &Dictionary) -> Uses {
+    // each use of a word is the transition to it, and each use of a pair of words is
+    // followed by exactly one transition
+    let mut uses = Uses {
+        transitions: 0,
+        words: HashMap::new(),
+        bigrams: HashMap::new(),
+    };
+    for (&(w1, w2), successors) in dict.entries() {
+        let total: u64 = successors.values().map(|&chance| chance as u64).sum();
+        uses.transitions += total;
+        for (&word, &chance) in successors {
+            if let Word::Word(_) = word {
+                *uses.words.entry(word).or_insert(0) += chance as u64;
+            }
+        }
+        if let (Word::Word(_), Word::Word(_)) = (w1, w2) {
+            *uses.bigrams.entry((w1, w2)).or_insert(0) += total;
+        }
+    }
+    uses
+}
+
+pub fn stats(program: &str, args: &[String]) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optopt("t",
+                "top",
+                &format!("how many of the top words and bigrams to list, {} by default",
+                         DEFAULT_TOP),
+                "N");
+    let (matches, dict) = match parse(program, "stats [options]", opts, args)? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    let count = number_opt(&matches, "t", DEFAULT_TOP)?;
+    let uses = count_uses(&dict);
+
+    println!("Words: {}", dict.word_count());
+    println!("Entries: {}", dict.entries().len());
+    println!("Transitions: {}", uses.transitions);
+    println!("\nTop words:");
+    for (word, uses) in top(uses.words, count) {
+        println!("{:>8}  {}", uses, word_name(&dict, word));
+    }
+    println!("\nTop bigrams:");
+    for ((w1, w2), uses) in top(uses.bigrams, count) {
+        println!("{:>8}  {} {}", uses, word_name(&dict, w1), word_name(&dict, w2));
+    }
+    Ok(())
+}
+
+pub fn generate(program: &str, args: &[String]) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optopt("n", "count", "how many sentences to generate, 1 by default", "N");
+    opts.optopt("s",
+                "seed",
+                "the seed of the random numbers, to get the same sentences again",
+                "S");
+    let (matches, dict) = match parse(program, "generate [options]", opts, args)? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    let count = number_opt(&matches, "n", 1)?;
+
+    match matches.opt_str("s") {
+        Some(seed) => {
+            let seed: usize = seed.parse().map_err(|_| format!("Invalid seed {}", seed))?;
+            let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+            for _ in 0..count {
+                println!("{}", dict.generate_sentence_with(&mut rng));
+            }
+        }
+        None => {
+            let mut rng = rand::thread_rng();
+            for _ in 0..count {
+                println!("{}", dict.generate_sentence_with(&mut rng));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn query(program: &str, args: &[String]) -> Result<(), String> {
+    let usage = format!("query [options] WORD1 WORD2\n\n{} stands for the start of a \
+                         sentence, and for its end in the output",
+                        START);
+    let (matches, dict) = match parse(program, &usage, Options::new(), args)? {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    if matches.free.len() != 2 {
+        return Err("Expected two words".to_string());
+    }
+    let (w1, w2) = query_pair(&dict, &matches.free[0], &matches.free[1])?;
+
+    let successors = match dict.successors(w1, w2) {
+        Some(successors) => successors,
+        None => {
+            println!("Nothing followed {} {}", word_name(&dict, w1), word_name(&dict, w2));
+            return Ok(());
+        }
+    };
+    let total: u64 = successors.values().map(|&chance| chance as u64).sum();
+    let mut successors: Vec<(Word, u32)> = successors
+        .iter()
+        .map(|(&word, &chance)| (word, chance))
+        .collect();
+    successors.sort_by(|a, b| b.1.cmp(&a.1));
+    for (word, chance) in successors {
+        println!("{:>8} {:>6.2}%  {}",
+                 chance,
+                 chance as f64 * 100.0 / total as f64,
+                 word_name(&dict, word));
+    }
+    Ok(())
+}
+
+/// The pair of words to query, where `START` stands for the start of a sentence
+fn query_pair(dict: &Dictionary, w1: &str, w2: &str) -> Result<(Word, Word), String> {
+    // a sentence starts with two markers, so the first word follows a single one
+    match (w1 == START, w2 == START) {
+        (true, true) => Ok((Word::Start1, Word::Start2)),
+        (true, false) => Ok((Word::Start2, lookup(dict, w2)?)),
+        (false, false) => Ok((lookup(dict, w1)?, lookup(dict, w2)?)),
+        (false, true) => Err(format!("{} can only start the pair", START)),
+    }
+}
+
+fn lookup(dict: &Dictionary, text: &str) -> Result<Word, String> {
+    dict.lookup(text)
+        .ok_or_else(|| format!("{} isn't in the dictionary", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary() -> Dictionary {
+        let mut dict = Dictionary::new();
+        dict.learn_from_line("the cat sat");
+        dict.learn_from_line("the cat ran");
+        dict.learn_from_line("a dog sat");
+        dict
+    }
+
+    fn word(dict: &Dictionary, text: &str) -> Word {
+        dict.lookup(text).unwrap()
+    }
+
+    #[test]
+    fn counts_the_uses_of_words_and_bigrams() {
+        let dict = dictionary();
+        let uses = count_uses(&dict);
+        // three words and the end of each sentence
+        assert_eq!(uses.transitions, 12);
+        assert_eq!(uses.words[&word(&dict, "the")], 2);
+        assert_eq!(uses.words[&word(&dict, "sat")], 2);
+        assert_eq!(uses.words[&word(&dict, "dog")], 1);
+        assert_eq!(uses.bigrams[&(word(&dict, "the"), word(&dict, "cat"))], 2);
+        assert_eq!(uses.bigrams[&(word(&dict, "cat"), word(&dict, "sat"))], 1);
+
+        let top_words: Vec<&str> = top(uses.words, 3)
+            .into_iter()
+            .map(|(word, _)| word_name(&dict, word))
+            .collect();
+        assert_eq!(top_words, vec!["the", "cat", "sat"]);
+    }
+
+    #[test]
+    fn reads_the_pair_to_query() {
+        let dict = dictionary();
+        // `Word` isn't `Debug`
+        assert!(query_pair(&dict, START, START) == Ok((Word::Start1, Word::Start2)));
+        assert!(query_pair(&dict, START, "The") == Ok((Word::Start2, word(&dict, "the"))));
+        assert!(query_pair(&dict, "cat", "sat") == Ok((word(&dict, "cat"), word(&dict, "sat"))));
+        assert!(query_pair(&dict, "cat", START).is_err());
+        assert!(query_pair(&dict, "cat", "flew").is_err());
+        assert_eq!(word_name(&dict, Word::End), END);
+    }
+
+    #[test]
+    fn generates_the_same_sentences_from_the_same_seed() {
+        let dict = dictionary();
+        let sentences = |seed: usize| -> Vec<String> {
+            let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+            (0..5).map(|_| dict.generate_sentence_with(&mut rng)).collect()
+        };
+        assert_eq!(sentences(7), sentences(7));
+    }
+}
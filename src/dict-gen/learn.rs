@@ -0,0 +1,287 @@
+//! The `learn` command, building a dictionary from logs.
+
+use chrono::NaiveDate;
+use dictionary::{Dictionary, logfile};
+use filter::{self, Filter};
+use format::{self, Choice, FileCount, LineFormat, Message};
+use getopts::{Matches, Options};
+use slack;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const DEFAULT_OUTPUT: &'static str = "dictionary.dat";
+/// Users whose nick contains one of these, like `lucidbot2`, are skipped unless `-N` is given
+const DEFAULT_EXCLUDE_NICKS: &'static [&'static str] = &["lucidbot"];
+const DEFAULT_COMMAND_PREFIXES: &'static [&'static str] = &["!"];
+
+/// How the lines of the logs are parsed
+struct Formats {
+    all: Vec<Box<LineFormat>>,
+    /// `None` to detect the format of each file
+    chosen: Option<Box<LineFormat>>,
+    /// Whether the private channels of the logs are learned from
+    private: bool,
+}
+
+#[derive(Default)]
+struct Summary {
+    files: usize,
+    unreadable: usize,
+    learned: usize,
+    skipped: usize,
+}
+
+/// Returns whether the file was learned from, rather than skipped as private
+fn learn_from_file<P: AsRef<Path> + Debug>(path: P,
+                                           base_dir: &Path,
+                                           formats: &Formats,
+                                           learn: &mut FnMut(Message))
+                                           -> io::Result<bool> {
+    let contents = logfile::read_to_string(&path)?;
+    let format = match formats.chosen {
+        Some(ref format) => &**format,
+        None => format::detect(&formats.all, &contents),
+    };
+    let channel = format.channel(base_dir, path.as_ref());
+    if !formats.private && channel.as_ref().map_or(false, |channel| format.is_private(channel)) {
+        println!("{:?} ({}, private, skipped)", &path, format.name());
+        return Ok(false);
+    }
+    println!("{:?} ({})...", &path, format.name());
+
+    let file_date = format::file_date(path.as_ref());
+    for line in contents.lines() {
+        if let Some(line) = format.parse(line) {
+            learn(Message {
+                      channel: channel.as_ref().map(|channel| channel.as_str()),
+                      nick: line.nick,
+                      text: line.message,
+                      date: line.date.or(file_date),
+                      bot: false,
+                  });
+        }
+    }
+    Ok(true)
+}
+
+/// Files which can't be read are reported, skipped and counted
+fn learn_from_dir<P: AsRef<Path>>(path: P,
+                                  base_dir: &Path,
+                                  formats: &Formats,
+                                  learn: &mut FnMut(Message))
+                                  -> io::Result<FileCount> {
+    let mut files = FileCount::default();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let filetype = entry.file_type()?;
+        if filetype.is_dir() {
+            let found = learn_from_dir(entry.path(), base_dir, formats, learn)?;
+            files.read += found.read;
+            files.unreadable += found.unreadable;
+        } else if filetype.is_file() &&
+                  logfile::uncompressed_path(entry.path())
+                      .extension()
+                      .map_or(true, |ext| ext != "jsonl") {
+            match learn_from_file(entry.path(), base_dir, formats, learn) {
+                Ok(true) => files.read += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Couldn't read {:?}: {}", entry.path(), e);
+                    files.unreadable += 1;
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn learn_from(base_dir: &Path,
+              choice: &Choice,
+              private: bool,
+              filter: &Filter,
+              learn: &mut FnMut(Message))
+              -> io::Result<FileCount> {
+    let chosen = match *choice {
+        Choice::Auto if slack::is_export(base_dir) => None,
+        Choice::SlackExport => None,
+        Choice::Auto => Some(None),
+        Choice::Format(name) => Some(format::by_name(name)),
+    };
+    match chosen {
+        Some(chosen) => {
+            let formats = Formats {
+                all: format::formats(),
+                chosen: chosen,
+                private: private,
+            };
+            learn_from_dir(base_dir, base_dir, &formats, learn)
+        }
+        None => {
+            let skip_channel = |channel: &str| !filter.wants_channel(Some(channel));
+            slack::import(base_dir, &skip_channel, learn)
+        }
+    }
+}
+
+/// The values of a list option, or the defaults if it isn't given; empty values are
+/// dropped, so that an empty value clears the defaults
+fn list_opt(matches: &Matches,
+            name: &str,
+            defaults: &[&str],
+            key: fn(&str) -> String)
+            -> Vec<String> {
+    let values = matches.opt_strs(name);
+    if values.is_empty() {
+        defaults.iter().map(|value| key(value)).collect()
+    } else {
+        values
+            .iter()
+            .filter(|value| !value.is_empty())
+            .map(|value| key(value))
+            .collect()
+    }
+}
+
+fn date_opt(matches: &Matches, name: &str) -> Result<Option<NaiveDate>, String> {
+    match matches.opt_str(name) {
+        Some(text) => {
+            format::parse_date(&text)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid date {}, expected YYYY-MM-DD", text))
+        }
+        None => Ok(None),
+    }
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("o",
+                "output",
+                &format!("the dictionary to write, {} by default", DEFAULT_OUTPUT),
+                "FILE");
+    opts.optflag("u",
+                 "update",
+                 "add to the existing dictionary instead of replacing it");
+    opts.optopt("f",
+                "format",
+                &format!("what the logs are: {}; auto (the default) detects a Slack export \
+                          or else the format of each file",
+                         format::choice_names()),
+                "FORMAT");
+    opts.optmulti("c",
+                  "channel",
+                  "only learn from this channel; can be given more than once",
+                  "CHANNEL");
+    opts.optmulti("C",
+                  "exclude-channel",
+                  "don't learn from this channel; can be given more than once",
+                  "CHANNEL");
+    opts.optmulti("n",
+                  "nick",
+                  "only learn from this user; can be given more than once",
+                  "NICK");
+    opts.optmulti("N",
+                  "exclude-nick",
+                  &format!("don't learn from this user; can be given more than once, an \
+                            empty one excludes nobody; by default, users whose nick contains \
+                            {} are excluded",
+                           DEFAULT_EXCLUDE_NICKS.join(" or ")),
+                  "NICK");
+    opts.optmulti("p",
+                  "command-prefix",
+                  &format!("skip the messages starting with this; can be given more than \
+                            once, {} by default, an empty one skips none",
+                           DEFAULT_COMMAND_PREFIXES.join(" ")),
+                  "PREFIX");
+    opts.optflag("",
+                 "private",
+                 "also learn from the bot's system log, direct messages and private \
+                  channels, which are skipped in its logs by default");
+    opts.optopt("", "from", "skip the messages before this day", "YYYY-MM-DD");
+    opts.optopt("", "to", "skip the messages after this day", "YYYY-MM-DD");
+    opts.optflag("h", "help", "print this help");
+    opts
+}
+
+/// Runs the command with the arguments after its name
+pub fn run(program: &str, args: &[String]) -> Result<(), String> {
+    let opts = options();
+    let matches = opts.parse(args).map_err(|e| e.to_string())?;
+    if matches.opt_present("h") {
+        print!("{}",
+               opts.usage(&format!("Usage: {} learn [options] LOG_DIR...", program)));
+        return Ok(());
+    }
+    if matches.free.is_empty() {
+        return Err("Required argument missing: base log directory".to_string());
+    }
+    let choice = format::parse_choice(&matches.opt_str("f").unwrap_or("auto".into()))?;
+    let filter = Filter {
+        nicks: list_opt(&matches, "n", &[], filter::nick_key),
+        exclude_nicks: list_opt(&matches, "N", &[], filter::nick_key),
+        exclude_nicks_containing: if matches.opt_present("N") {
+            Vec::new()
+        } else {
+            DEFAULT_EXCLUDE_NICKS.iter().map(|nick| filter::nick_key(nick)).collect()
+        },
+        channels: list_opt(&matches, "c", &[], filter::channel_key),
+        exclude_channels: list_opt(&matches, "C", &[], filter::channel_key),
+        command_prefixes: list_opt(&matches, "p", DEFAULT_COMMAND_PREFIXES, str::to_string),
+        from: date_opt(&matches, "from")?,
+        to: date_opt(&matches, "to")?,
+    };
+    let output = matches.opt_str("o").unwrap_or(DEFAULT_OUTPUT.into());
+
+    let mut dict = if matches.opt_present("u") {
+        match Dictionary::load_or_backup(&output) {
+            Ok((dict, backup)) => {
+                if let Some(backup) = backup {
+                    println!("{} couldn't be read, updating its backup {}",
+                             output,
+                             backup.display());
+                }
+                dict
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Dictionary::new(),
+            Err(e) => return Err(format!("Couldn't read {}: {}", output, e)),
+        }
+    } else {
+        Dictionary::new()
+    };
+    let words = dict.word_count();
+
+    let mut summary = Summary::default();
+    for base_dir in &matches.free {
+        let files = {
+            let mut learn = |message: Message| if filter.wants(&message) {
+                dict.learn_from_line(message.text);
+                summary.learned += 1;
+            } else {
+                summary.skipped += 1;
+            };
+            learn_from(Path::new(base_dir),
+                       &choice,
+                       matches.opt_present("private"),
+                       &filter,
+                       &mut learn)
+                .map_err(|e| format!("Couldn't read {}: {}", base_dir, e))?
+        };
+        summary.files += files.read;
+        summary.unreadable += files.unreadable;
+    }
+
+    dict.save(&output)
+        .map_err(|e| format!("Couldn't save {}: {}", output, e))?;
+    println!("Read {} files, learned {} lines, skipped {}, {} new words; saved {}",
+             summary.files,
+             summary.learned,
+             summary.skipped,
+             dict.word_count() - words,
+             output);
+    if summary.unreadable > 0 {
+        eprintln!("Couldn't read {} files, see above", summary.unreadable);
+    }
+    Ok(())
+}
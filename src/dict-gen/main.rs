@@ -1,62 +1,48 @@
+extern crate chrono;
+extern crate getopts;
+extern crate rand;
 extern crate regex;
+extern crate serde_json;
 extern crate dictionary;
 
-use dictionary::Dictionary;
-use regex::Regex;
-use std::env::args;
-use std::fmt::Debug;
-use std::fs;
-use std::io::Read;
-use std::path::Path;
-
-fn learn_from_file<P: AsRef<Path> + Debug>(path: P, dict: &mut Dictionary) {
-    println!("{:?}...", &path);
-
-    let mut file = fs::File::open(path).unwrap();
-    let mut contents = String::new();
-    let _ = file.read_to_string(&mut contents);
-
-    let rx_line =
-        Regex::new(r"\(\d\d\d\d-\d\d-\d\d \d\d:\d\d:\d\d\)\s*<(?P<nick>[^>]+)> (?P<message>.*)")
-            .unwrap();
+mod filter;
+mod format;
+mod inspect;
+mod learn;
+mod slack;
 
-    for line in contents.lines() {
-        if let Some(caps) = rx_line.captures(line) {
-            let nick = caps.name("nick").unwrap();
-            let msg = caps.name("message").unwrap();
-
-            if !nick.to_lowercase().contains("lucidbot") && !msg.starts_with('!') {
-                dict.learn_from_line(msg);
-            }
-        }
-    }
-}
+use std::env::args;
+use std::process;
 
-fn learn_from_dir<P: AsRef<Path>>(path: P, dict: &mut Dictionary) {
-    let dir_content = fs::read_dir(path).unwrap();
+const USAGE: &'static str = "Commands:
+    learn [options] LOG_DIR...
+        builds a dictionary from logs; it is the default command
+    stats [options]
+        prints the size of a dictionary and its most used words
+    generate [options]
+        generates sentences, as the bot would
+    query [options] WORD1 WORD2
+        prints the words which can follow two words, and their chances
 
-    for entry in dir_content {
-        if let Ok(entry) = entry {
-            let filetype = entry.file_type().unwrap();
-            if filetype.is_dir() {
-                learn_from_dir(entry.path(), dict);
-            } else if filetype.is_file() {
-                learn_from_file(entry.path(), dict);
-            }
-        }
-    }
-}
+Run a command with --help for its options.";
 
 fn main() {
     let args = args().collect::<Vec<String>>();
-    if args.len() < 2 {
-        println!("Required argument missing: base log directory");
-        return;
+    let program = &args[0];
+    let result = match args.get(1).map(|arg| arg.as_str()) {
+        None | Some("help") | Some("-h") | Some("--help") => {
+            println!("Usage: {} COMMAND [options]\n\n{}", program, USAGE);
+            Ok(())
+        }
+        Some("learn") => learn::run(program, &args[2..]),
+        Some("stats") => inspect::stats(program, &args[2..]),
+        Some("generate") => inspect::generate(program, &args[2..]),
+        Some("query") => inspect::query(program, &args[2..]),
+        // the logs used to be the only argument
+        Some(_) => learn::run(program, &args[1..]),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
     }
-    let base_dir = &args[1];
-    let mut dict = Dictionary::new();
-
-    learn_from_dir(base_dir, &mut dict);
-
-    let _ = dict.save("dictionary.dat");
 }
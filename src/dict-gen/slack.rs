@@ -0,0 +1,184 @@
+//! Importing a Slack workspace export.
+//!
+//! An export has `users.json` and `channels.json` at the top, and a directory per channel
+//! holding a `YYYY-MM-DD.json` array of the messages of each day.
+
+use format::{self, FileCount, Message};
+use regex::{Captures, Regex};
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+/// The name of the export in the `--format` option
+pub const NAME: &'static str = "slack";
+
+const USERS_FILE: &'static str = "users.json";
+const CHANNELS_FILE: &'static str = "channels.json";
+/// Slack's markup for mentions and links, like `<@U123>` or `<http://example.com|example>`
+const MARKUP: &'static str = r"<([^>]*)>";
+
+/// The subtypes of the messages written by users; the others are joins, topic changes and
+/// the like
+const USER_SUBTYPES: &'static [&'static str] = &["me_message", "thread_broadcast", "file_share"];
+
+struct User {
+    name: String,
+    bot: bool,
+}
+
+pub fn is_export<P: AsRef<Path>>(dir: P) -> bool {
+    let dir = dir.as_ref();
+    dir.join(USERS_FILE).is_file() && dir.join(CHANNELS_FILE).is_file()
+}
+
+fn read_json(path: &Path) -> io::Result<Value> {
+    serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_users(dir: &Path) -> io::Result<HashMap<String, User>> {
+    let mut users = HashMap::new();
+    if let Value::Array(list) = read_json(&dir.join(USERS_FILE))? {
+        for user in list {
+            let id = match user["id"].as_str() {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let name = user["profile"]["display_name"]
+                .as_str()
+                .into_iter()
+                .chain(user["name"].as_str())
+                .find(|name| !name.is_empty())
+                .unwrap_or(&id)
+                .to_string();
+            let bot = user["is_bot"].as_bool().unwrap_or(false) || id == "USLACKBOT";
+            users.insert(id,
+                         User {
+                             name: name,
+                             bot: bot,
+                         });
+        }
+    }
+    Ok(users)
+}
+
+/// Turns Slack's markup back into what the user typed: `<@U123>` into `@name`,
+/// `<#C123|general>` into `#general` and links into their text
+fn unescape(text: &str, users: &HashMap<String, User>, markup: &Regex) -> String {
+    let text = markup.replace_all(text, |caps: &Captures| {
+        let inner = caps.at(1).unwrap_or("");
+        let mut parts = inner.splitn(2, '|');
+        let target = parts.next().unwrap_or("");
+        let label = parts.next();
+        if target.starts_with('@') {
+            let name = label.or_else(|| users.get(&target[1..]).map(|user| user.name.as_str()));
+            format!("@{}", name.unwrap_or(&target[1..]))
+        } else if target.starts_with('#') {
+            format!("#{}", label.unwrap_or(&target[1..]))
+        } else if target.starts_with('!') {
+            // the label of `<!here|@here>` has the `@` already
+            format!("@{}", label.unwrap_or(&target[1..]).trim_left_matches('@'))
+        } else {
+            label.unwrap_or(target).to_string()
+        }
+    });
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Reads the messages of an export, channel by channel and day by day. Files which can't
+/// be read are reported, skipped and counted.
+pub fn import<P: AsRef<Path>>(dir: P,
+                              skip_channel: &Fn(&str) -> bool,
+                              learn: &mut FnMut(Message))
+                              -> io::Result<FileCount> {
+    let dir = dir.as_ref();
+    let users = read_users(dir)?;
+    let markup = Regex::new(MARKUP).unwrap();
+    let mut files = FileCount::default();
+
+    for channel_dir in sorted_entries(dir)? {
+        if !channel_dir.is_dir() {
+            continue;
+        }
+        let channel = channel_dir.file_name().unwrap().to_string_lossy().into_owned();
+        if skip_channel(&channel) {
+            continue;
+        }
+        for path in sorted_entries(&channel_dir)? {
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            println!("{:?}...", &path);
+            let messages = match read_json(&path) {
+                Ok(Value::Array(messages)) => messages,
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("Couldn't read {:?}: {}", &path, e);
+                    files.unreadable += 1;
+                    continue;
+                }
+            };
+            files.read += 1;
+            let date = format::file_date(&path);
+            for message in messages {
+                let subtype = message["subtype"].as_str();
+                if subtype.map_or(false, |subtype| !USER_SUBTYPES.contains(&subtype)) {
+                    continue;
+                }
+                let text = match message["text"].as_str() {
+                    Some(text) => unescape(text, &users, &markup),
+                    None => continue,
+                };
+                let user = message["user"].as_str().and_then(|id| users.get(id));
+                learn(Message {
+                          channel: Some(&channel),
+                          nick: user.map_or("", |user| user.name.as_str()),
+                          text: &text,
+                          date: date,
+                          bot: message["bot_id"].is_string() || user.map_or(false, |user| user.bot),
+                      });
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_the_markup() {
+        let mut users = HashMap::new();
+        users.insert("U123".to_string(),
+                     User {
+                         name: "alice".to_string(),
+                         bot: false,
+                     });
+        let markup = Regex::new(MARKUP).unwrap();
+        let cases = [("hi <@U123>", "hi @alice"),
+                     ("<@U123|al> hi", "@al hi"),
+                     ("<@U999>", "@U999"),
+                     ("see <#C123|general>", "see #general"),
+                     ("<#C123>", "#C123"),
+                     ("<!here> <!channel|@channel>", "@here @channel"),
+                     ("<https://example.com>", "https://example.com"),
+                     ("<https://example.com|the site>", "the site"),
+                     ("a &lt;b&gt; &amp;amp; c", "a <b> &amp; c"),
+                     ("&lt;@U123&gt;", "<@U123>")];
+        for &(text, expected) in &cases {
+            assert_eq!(unescape(text, &users, &markup), expected, "{}", text);
+        }
+    }
+}
@@ -1,10 +1,17 @@
+extern crate flate2;
 extern crate rand;
 
+pub mod logfile;
+pub mod persist;
+
 use rand::Rng;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, hash_map};
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// The number of previous versions kept by `Dictionary::save`
+pub const BACKUPS: usize = 3;
 
 fn to_4u8(x: u32) -> [u8; 4] {
     let mut result = [0; 4];
@@ -59,7 +66,7 @@ impl Word {
         }
 
         match bytes[0] {
-            0 => Some(Word::Word(to_u32(&bytes[1..5]).unwrap())),
+            0 => to_u32(&bytes[1..5]).map(Word::Word),
             1 => Some(Word::Start1),
             2 => Some(Word::Start2),
             0xFF => Some(Word::End),
@@ -83,25 +90,32 @@ impl<'a> ByteReader<'a> {
         }
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let result = to_u32(&self.bytes[self.cursor..self.cursor + 4]).unwrap();
-        self.cursor += 4;
-        result
+    /// The next `len` bytes, or `None` if the input is too short
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() - self.cursor < len {
+            return None;
+        }
+        let result = &self.bytes[self.cursor..self.cursor + len];
+        self.cursor += len;
+        Some(result)
     }
 
-    fn read_word(&mut self) -> Word {
-        let result = Word::from_bytes(&self.bytes[self.cursor..self.cursor + 5]).unwrap();
-        self.cursor += 5;
-        result
+    fn read_u32(&mut self) -> Option<u32> {
+        self.take(4).and_then(to_u32)
+    }
+
+    fn read_word(&mut self) -> Option<Word> {
+        self.take(5).and_then(Word::from_bytes)
     }
 
-    fn read_string(&mut self) -> String {
-        let word_length = to_u32(&self.bytes[self.cursor..self.cursor + 4]).unwrap() as usize;
-        self.cursor += 4;
-        let word = ::std::str::from_utf8(&self.bytes[self.cursor..self.cursor + word_length])
-            .unwrap();
-        self.cursor += word_length;
-        word.to_string()
+    fn read_string(&mut self) -> Option<String> {
+        let word_length = self.read_u32()? as usize;
+        let bytes = self.take(word_length)?;
+        ::std::str::from_utf8(bytes).ok().map(|word| word.to_string())
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.cursor == self.bytes.len()
     }
 }
 
@@ -152,34 +166,50 @@ impl Dictionary {
         result
     }
 
+    /// Decodes a saved dictionary, or returns `None` if the data is truncated or corrupt
     fn from_bytes(bytes: &[u8]) -> Option<Dictionary> {
         let mut reader = ByteReader::new(bytes);
-        let num_words = reader.read_u32();
+        let num_words = reader.read_u32()?;
         let mut words = Vec::new();
         let mut index_map = HashMap::new();
         // read words
         for i in 0..num_words {
-            let word = reader.read_string();
+            let word = reader.read_string()?;
             words.push(word.clone());
             index_map.insert(word.to_lowercase(), i as usize);
         }
+        // a word outside of the word list would crash generate_sentence
+        let valid = |word: Word| match word {
+            Word::Word(index) => (index as usize) < words.len(),
+            _ => true,
+        };
         // read entry map
-        let num_entries = reader.read_u32();
+        let num_entries = reader.read_u32()?;
         let mut hashmap = HashMap::new();
         for _ in 0..num_entries {
             // first entry word
-            let word1 = reader.read_word();
+            let word1 = reader.read_word()?;
             // second entry word
-            let word2 = reader.read_word();
-            let num_results = reader.read_u32();
+            let word2 = reader.read_word()?;
+            let num_results = reader.read_u32()?;
             let mut results = BTreeMap::new();
             for _ in 0..num_results {
-                let word = reader.read_word();
-                let chance = reader.read_u32();
+                let word = reader.read_word()?;
+                let chance = reader.read_u32()?;
+                if !valid(word) || chance == 0 {
+                    return None;
+                }
                 results.insert(word, chance);
             }
+            // nothing to choose from would crash choosing the next word
+            if !valid(word1) || !valid(word2) || results.is_empty() {
+                return None;
+            }
             hashmap.insert((word1, word2), results);
         }
+        if !reader.is_at_end() {
+            return None;
+        }
         Some(Dictionary {
                  words: words,
                  index_map: index_map,
@@ -187,11 +217,9 @@ impl Dictionary {
              })
     }
 
+    /// Saves the dictionary atomically, keeping `BACKUPS` previous versions
     pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
-        let mut file = File::create(path)?;
-        let bytes = self.to_bytes();
-        file.write_all(&bytes)?;
-        Ok(())
+        persist::write_atomic(path, &self.to_bytes(), BACKUPS)
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Dictionary> {
@@ -205,6 +233,18 @@ impl Dictionary {
         }
     }
 
+    /// Loads the dictionary, falling back to the newest backup that is valid.
+    ///
+    /// The second value is the backup used, if the file itself couldn't be loaded.
+    pub fn load_or_backup<P: AsRef<Path>>(path: P) -> io::Result<(Dictionary, Option<PathBuf>)> {
+        persist::load_with_backups(path, BACKUPS, |path| Dictionary::load(path))
+    }
+
+    /// The number of distinct words, ignoring the case
+    pub fn word_count(&self) -> usize {
+        self.words.len()
+    }
+
     fn insert_word<S: AsRef<str>>(&mut self, word: S) -> usize {
         if let Some(index) = self.index_map.get(&word.as_ref().to_lowercase()) {
             return *index;
@@ -239,8 +279,32 @@ impl Dictionary {
         }
     }
 
-    fn get_next_word(&self, w1: Word, w2: Word) -> Option<Word> {
-        let mut rng = rand::thread_rng();
+    /// The text of a word; `None` for the start and end markers
+    pub fn word_text(&self, word: Word) -> Option<&str> {
+        match word {
+            Word::Word(index) => self.words.get(index as usize).map(|word| word.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The word of a text, ignoring the case
+    pub fn lookup<S: AsRef<str>>(&self, text: S) -> Option<Word> {
+        self.index_map
+            .get(&text.as_ref().to_lowercase())
+            .map(|index| Word::Word(*index as u32))
+    }
+
+    /// The words which followed the pair, with the number of times each did
+    pub fn successors(&self, w1: Word, w2: Word) -> Option<&BTreeMap<Word, u32>> {
+        self.dict.get(&(w1, w2))
+    }
+
+    /// All the pairs of words, with the words which followed them
+    pub fn entries(&self) -> hash_map::Iter<Entry, BTreeMap<Word, u32>> {
+        self.dict.iter()
+    }
+
+    fn get_next_word<R: Rng>(&self, w1: Word, w2: Word, rng: &mut R) -> Option<Word> {
         let possibilities;
         if let Some(p) = self.dict.get(&(w1, w2)) {
             possibilities = p;
@@ -264,13 +328,19 @@ impl Dictionary {
     }
 
     pub fn generate_sentence(&self) -> String {
+        self.generate_sentence_with(&mut rand::thread_rng())
+    }
+
+    /// Generates a sentence with the given random number generator, which can be seeded
+    /// to get the same sentences again
+    pub fn generate_sentence_with<R: Rng>(&self, rng: &mut R) -> String {
         let mut w1 = Word::Start1;
         let mut w2 = Word::Start2;
 
         let mut words = Vec::new();
         loop {
             let next_word;
-            if let Some(nw) = self.get_next_word(w1, w2) {
+            if let Some(nw) = self.get_next_word(w1, w2, rng) {
                 next_word = nw;
             } else {
                 break;
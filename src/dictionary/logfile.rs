@@ -0,0 +1,74 @@
+//! Reading and compressing log files.
+//!
+//! Old logs are gzipped in place, `DD.txt` becoming `DD.txt.gz`, so anything that reads
+//! the logs should open them with `open` or `read_to_string`.
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use persist;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// The directory of the bot's core and system events, next to the channel directories.
+/// `~` is replaced in channel directory names, so it never clashes with a channel.
+pub const SYSTEM_STREAM: &'static str = "~system";
+
+/// Whether a directory of the bot's logs holds no public chat: the system events, or a
+/// direct message or private channel, which are logged under names starting with `@`
+pub fn is_private_stream(name: &str) -> bool {
+    name == SYSTEM_STREAM || name.starts_with('@')
+}
+
+pub fn is_compressed<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension() == Some(OsStr::new("gz"))
+}
+
+/// The path of a log without the `.gz` extension, if it is compressed
+pub fn uncompressed_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    if is_compressed(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Opens a log for reading, decompressing it if needed
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<BufRead>> {
+    let file = File::open(&path)?;
+    if is_compressed(&path) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Reads a whole log, decompressing it if needed.
+///
+/// Invalid UTF-8 is replaced rather than failing the whole file.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    open(path)?.read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Replaces a log with its gzipped version; returns the path of the new file
+pub fn compress<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    let mut target = path.as_os_str().to_os_string();
+    target.push(".gz");
+    let target = PathBuf::from(target);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // lines written after an earlier compression are appended to the compressed ones
+    if target.exists() {
+        io::copy(&mut open(&target)?, &mut encoder)?;
+    }
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    persist::write_atomic(&target, &encoder.finish()?, 0)?;
+    fs::remove_file(path)?;
+    Ok(target)
+}
@@ -0,0 +1,99 @@
+//! Crash-safe writing of data files.
+//!
+//! A file is never truncated in place: the new contents go to a temporary file next to it,
+//! which is synced to disk and then renamed over the old one. The previous versions are
+//! kept as `<file>.1` (the newest) up to `<file>.N`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The path of the `n`-th backup of a file, `n` starting at 1
+pub fn backup_path<P: AsRef<Path>>(path: P, n: usize) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Shifts the backups by one, dropping the oldest, and makes the current file the first backup
+fn rotate(path: &Path, backups: usize) -> io::Result<()> {
+    if backups == 0 || !path.exists() {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let older = backup_path(path, n);
+        if older.exists() {
+            fs::rename(&older, backup_path(path, n + 1))?;
+        }
+    }
+    // a copy rather than a rename, so that the file exists at all times
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Replaces the contents of a file, keeping up to `backups` previous versions
+pub fn write_atomic<P: AsRef<Path>>(path: P, bytes: &[u8], backups: usize) -> io::Result<()> {
+    let path = path.as_ref();
+    let temp = temp_path(path);
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        rotate(path, backups)?;
+        fs::rename(&temp, path)?;
+        sync_dir(path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Loads a file with `load`, falling back to the newest backup that loads.
+///
+/// Returns the loaded value and the backup it came from, if the file itself couldn't be
+/// loaded. If nothing loads, the error is the one of the file itself.
+pub fn load_with_backups<P, T, F>(path: P,
+                                  backups: usize,
+                                  load: F)
+                                  -> io::Result<(T, Option<PathBuf>)>
+    where P: AsRef<Path>,
+          F: Fn(&Path) -> io::Result<T>
+{
+    let path = path.as_ref();
+    let error = match load(path) {
+        Ok(value) => return Ok((value, None)),
+        Err(e) => e,
+    };
+    for n in 1..backups + 1 {
+        let backup = backup_path(path, n);
+        if let Ok(value) = load(&backup) {
+            return Ok((value, Some(backup)));
+        }
+    }
+    Err(error)
+}
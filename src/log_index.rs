@@ -0,0 +1,486 @@
+use chrono::{Duration, NaiveDate};
+use dictionary::logfile;
+use logger;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A chat line of a log
+#[derive(Clone)]
+pub struct LogLine {
+    /// `YYYY-MM-DD HH:MM:SS`, in the time zone of the logs
+    pub time: String,
+    /// The user's name, with the role prefix
+    pub nick: String,
+    pub text: String,
+}
+
+impl LogLine {
+    /// Parses a `[YYYY-MM-DD HH:MM:SS] <nick> text` line; other lines aren't chat
+    fn parse(line: &str) -> Option<LogLine> {
+        if !line.starts_with('[') {
+            return None;
+        }
+        let stamp_end = line.find("] <")?;
+        let time = &line[1..stamp_end];
+        let rest = &line[stamp_end + 3..];
+        let nick_end = rest.find("> ").or_else(|| if rest.ends_with('>') {
+                                                       Some(rest.len() - 1)
+                                                   } else {
+                                                       None
+                                                   })?;
+        Some(LogLine {
+                 time: time.to_string(),
+                 nick: rest[..nick_end].to_string(),
+                 text: rest.get(nick_end + 2..).unwrap_or("").to_string(),
+             })
+    }
+
+    /// The user's name without the role prefix, lowercase
+    pub fn user(&self) -> String {
+        user_key(&self.nick)
+    }
+}
+
+pub fn user_key(name: &str) -> String {
+    name.trim_left_matches(|c| c == '&' || c == '~' || c == '@')
+        .to_lowercase()
+}
+
+/// Where and when a user was last seen
+pub struct Seen {
+    pub channel: Option<String>,
+    pub line: LogLine,
+}
+
+/// A search result
+pub struct Match<'a> {
+    pub channel: Option<&'a str>,
+    pub line: &'a LogLine,
+}
+
+struct IndexedFile {
+    /// The day of the file; it also holds the lines logged after midnight until the rollover
+    date: NaiveDate,
+    /// `None` for the logs from before they were split by channel
+    channel: Option<String>,
+    /// The logs are only appended to, so a longer file only needs its new lines read
+    len: u64,
+    compressed: bool,
+    users: HashSet<String>,
+    lines: Vec<LogLine>,
+}
+
+/// An in-memory index of the chat lines of the text logs, by date and user.
+///
+/// `refresh` brings it up to date with the files; it only reads what was appended to the
+/// logs since, and notices logs which were compressed or deleted.
+pub struct LogIndex {
+    base_dir: PathBuf,
+    /// By the path of the uncompressed log, which stays the same after the compression
+    files: HashMap<PathBuf, IndexedFile>,
+    seen: HashMap<String, Seen>,
+    /// The user whose lines are left out, as `user_key`
+    ignored: Option<String>,
+}
+
+/// The channel of a log at `<base>/<channel>/YYYY/MM/DD.txt`, or `None` for a log at
+/// `<base>/YYYY/MM/DD.txt`
+fn channel_of(base_dir: &Path, path: &Path) -> Option<String> {
+    let channel_dir = path.parent()?.parent()?.parent()?;
+    if channel_dir == base_dir {
+        None
+    } else {
+        channel_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+fn is_text_log(path: &Path) -> bool {
+    logfile::uncompressed_path(path)
+        .extension()
+        .map_or(false, |ext| ext == "txt")
+}
+
+fn collect_logs(dir: &Path, result: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+            if name.map_or(true, |name| !logfile::is_private_stream(&name)) {
+                collect_logs(&path, result)?;
+            }
+        } else if is_text_log(&path) {
+            result.push(path);
+        }
+    }
+    Ok(())
+}
+
+impl LogIndex {
+    pub fn new<P: AsRef<Path>>(base_dir: P) -> LogIndex {
+        LogIndex {
+            base_dir: base_dir.as_ref().to_path_buf(),
+            files: HashMap::new(),
+            seen: HashMap::new(),
+            ignored: None,
+        }
+    }
+
+    /// Leaves out the lines of a user from now on, and drops those already read. The bot's
+    /// own replies are logged too, so its `!grep` results would match the next searches.
+    pub fn ignore_user(&mut self, user: &str) {
+        let user = user_key(user);
+        if self.ignored.as_ref() == Some(&user) {
+            return;
+        }
+        for file in self.files.values_mut() {
+            if file.users.remove(&user) {
+                file.lines.retain(|line| line.user() != user);
+            }
+        }
+        self.seen.remove(&user);
+        self.ignored = Some(user);
+    }
+
+    /// Reads new and changed logs; returns the errors of the files that couldn't be read
+    pub fn refresh(&mut self) -> Vec<String> {
+        let mut paths = Vec::new();
+        if self.base_dir.exists() {
+            if let Err(e) = collect_logs(&self.base_dir, &mut paths) {
+                return vec![format!("{}: {}", self.base_dir.display(), e)];
+            }
+        }
+
+        let mut errors = Vec::new();
+        let mut present = HashSet::new();
+        for path in paths {
+            let key = logfile::uncompressed_path(&path);
+            // while being compressed, a log may briefly exist in both forms
+            if !present.insert(key.clone()) {
+                continue;
+            }
+            if let Err(e) = self.update_file(&path, key) {
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+        self.files.retain(|key, _| present.contains(key));
+        errors
+    }
+
+    fn update_file(&mut self, path: &Path, key: PathBuf) -> io::Result<()> {
+        let date = match logger::log_date(path) {
+            Some(date) => date,
+            None => return Ok(()),
+        };
+        let compressed = logfile::is_compressed(path);
+        let (start, lines) = match (self.files.get_mut(&key), compressed) {
+            // compressed logs don't change, but may have been compressed since indexed
+            (Some(file), true) => {
+                file.compressed = true;
+                return Ok(());
+            }
+            (Some(ref mut file), false) if !file.compressed => {
+                let len = fs::metadata(path)?.len();
+                if len == file.len {
+                    return Ok(());
+                }
+                let start = if len > file.len { file.len } else { 0 };
+                let mut reader = BufReader::new(File::open(path)?);
+                reader.seek(SeekFrom::Start(start))?;
+                let (lines, read) = read_lines(reader)?;
+                file.len = start + read;
+                (start, lines)
+            }
+            _ => {
+                let (lines, read) = read_lines(logfile::open(path)?)?;
+                let len = if compressed { 0 } else { read };
+                self.files
+                    .insert(key.clone(),
+                            IndexedFile {
+                                date: date,
+                                channel: channel_of(&self.base_dir, &key),
+                                len: len,
+                                compressed: compressed,
+                                users: HashSet::new(),
+                                lines: Vec::new(),
+                            });
+                (0, lines)
+            }
+        };
+
+        let file = self.files.get_mut(&key).unwrap();
+        if start == 0 {
+            file.users.clear();
+            file.lines.clear();
+        }
+        for line in lines {
+            let user = line.user();
+            if self.ignored.as_ref() == Some(&user) {
+                continue;
+            }
+            let newer = self.seen
+                .get(&user)
+                .map_or(true, |seen| seen.line.time <= line.time);
+            if newer {
+                self.seen
+                    .insert(user.clone(),
+                            Seen {
+                                channel: file.channel.clone(),
+                                line: line.clone(),
+                            });
+            }
+            file.users.insert(user);
+            file.lines.push(line);
+        }
+        Ok(())
+    }
+
+    pub fn seen(&self, user: &str) -> Option<&Seen> {
+        self.seen.get(&user_key(user))
+    }
+
+    /// The newest lines accepted by `filter`, newest first
+    pub fn search(&self,
+                  filter: &Fn(&LogLine) -> bool,
+                  user: Option<&str>,
+                  from: Option<NaiveDate>,
+                  to: Option<NaiveDate>,
+                  limit: usize)
+                  -> Vec<Match> {
+        let user = user.map(user_key);
+        let from_str = from.map(|date| date.format("%Y-%m-%d").to_string());
+        let to_str = to.map(|date| date.format("%Y-%m-%d").to_string());
+        let mut files: Vec<&IndexedFile> = self.files
+            .values()
+            // a file may hold lines of the next day as well
+            .filter(|file| from.map_or(true, |from| file.date >= from - Duration::days(1)))
+            .filter(|file| to.map_or(true, |to| file.date <= to))
+            .filter(|file| user.as_ref().map_or(true, |user| file.users.contains(user)))
+            .collect();
+        files.sort_by(|a, b| b.date.cmp(&a.date));
+
+        let wanted = |line: &LogLine| {
+            let day = &line.time[..line.time.len().min(10)];
+            user.as_ref().map_or(true, |user| line.user() == *user) &&
+            from_str.as_ref().map_or(true, |from| day >= from.as_str()) &&
+            to_str.as_ref().map_or(true, |to| day <= to.as_str()) &&
+            filter(line)
+        };
+
+        // the files of a day only hold lines newer than the files of the days before,
+        // so the search can stop after the day which fills the limit
+        let mut result = Vec::new();
+        let mut rest = &files[..];
+        while !rest.is_empty() {
+            let day_len = rest.iter()
+                .take_while(|file| file.date == rest[0].date)
+                .count();
+            let (day, later) = rest.split_at(day_len);
+            rest = later;
+            let mut matches: Vec<Match> = day.iter()
+                .flat_map(|file| {
+                    file.lines
+                        .iter()
+                        .filter(|line| wanted(line))
+                        .map(move |line| {
+                                 Match {
+                                     channel: file.channel.as_ref().map(|s| s.as_str()),
+                                     line: line,
+                                 }
+                             })
+                })
+                .collect();
+            matches.sort_by(|a, b| b.line.time.cmp(&a.line.time));
+            result.extend(matches);
+            if result.len() >= limit {
+                result.truncate(limit);
+                break;
+            }
+        }
+        result
+    }
+}
+
+/// Reads the chat lines up to the last complete one; returns them and the bytes read
+fn read_lines<R: BufRead>(mut reader: R) -> io::Result<(Vec<LogLine>, u64)> {
+    let mut lines = Vec::new();
+    let mut read = 0;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let len = reader.read_until(b'\n', &mut buf)?;
+        // a line without the newline is still being written
+        if len == 0 || buf.last() != Some(&b'\n') {
+            break;
+        }
+        read += len as u64;
+        if let Some(line) = LogLine::parse(String::from_utf8_lossy(&buf).trim_right()) {
+            lines.push(line);
+        }
+    }
+    Ok((lines, read))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lucidbot-index-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append(base: &Path, log: &str, text: &str) {
+        let path = base.join(log);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn everything(_: &LogLine) -> bool {
+        true
+    }
+
+    fn date(text: &str) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()
+    }
+
+    /// The texts of the lines found, newest first
+    fn texts(index: &LogIndex,
+             user: Option<&str>,
+             from: Option<NaiveDate>,
+             to: Option<NaiveDate>,
+             limit: usize)
+             -> Vec<String> {
+        index
+            .search(&everything, user, from, to, limit)
+            .iter()
+            .map(|found| found.line.text.clone())
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_appended_lines_once_they_are_complete() {
+        let dir = temp_dir("append");
+        let log = "general/2017/01/31.txt";
+        append(&dir, log, "[2017-01-31 10:00:00] <alice> one\n*** Connected ***\n");
+        let mut index = LogIndex::new(&dir);
+        assert!(index.refresh().is_empty());
+        assert_eq!(texts(&index, None, None, None, 10), vec!["one"]);
+
+        append(&dir, log, "[2017-01-31 10:01:00] <bob> two\n[2017-01-31 10:02:00] <alice> th");
+        index.refresh();
+        assert_eq!(texts(&index, None, None, None, 10), vec!["two", "one"]);
+
+        append(&dir, log, "ree\n");
+        index.refresh();
+        assert_eq!(texts(&index, None, None, None, 10), vec!["three", "two", "one"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follows_the_rollover_and_the_compression() {
+        let dir = temp_dir("rollover");
+        append(&dir,
+               "general/2017/01/31.txt",
+               "[2017-01-31 23:59:00] <alice> late\n\
+                [2017-02-01 00:00:05] <alice> after midnight\n");
+        let mut index = LogIndex::new(&dir);
+        index.refresh();
+
+        append(&dir, "general/2017/02/01.txt", "[2017-02-01 09:00:00] <bob> morning\n");
+        index.refresh();
+        let feb = date("2017-02-01");
+        assert_eq!(texts(&index, None, feb, feb, 10), vec!["morning", "after midnight"]);
+
+        logfile::compress(dir.join("general/2017/01/31.txt")).unwrap();
+        assert!(index.refresh().is_empty());
+        assert_eq!(texts(&index, None, None, None, 10),
+                   vec!["morning", "after midnight", "late"]);
+
+        fs::remove_file(dir.join("general/2017/02/01.txt")).unwrap();
+        index.refresh();
+        assert_eq!(texts(&index, None, None, None, 10), vec!["after midnight", "late"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn limits_and_filters_the_results() {
+        let dir = temp_dir("filters");
+        for day in 1..4 {
+            append(&dir,
+                   &format!("general/2017/03/0{}.txt", day),
+                   &format!("[2017-03-0{0} 10:00:00] <alice> a{0}\n\
+                             [2017-03-0{0} 11:00:00] <~Bob> b{0}\n",
+                            day));
+        }
+        let mut index = LogIndex::new(&dir);
+        index.refresh();
+        assert_eq!(texts(&index, None, None, None, 3), vec!["b3", "a3", "b2"]);
+        assert_eq!(texts(&index, Some("bob"), None, None, 10), vec!["b3", "b2", "b1"]);
+        assert_eq!(texts(&index, None, date("2017-03-02"), date("2017-03-02"), 10),
+                   vec!["b2", "a2"]);
+        assert_eq!(texts(&index, Some("@alice"), date("2017-03-02"), None, 10),
+                   vec!["a3", "a2"]);
+        assert_eq!(texts(&index, None, None, date("2017-03-01"), 10), vec!["b1", "a1"]);
+        assert!(texts(&index, Some("carol"), None, None, 10).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remembers_where_a_user_was_last_seen() {
+        let dir = temp_dir("seen");
+        append(&dir, "general/2017/01/31.txt", "[2017-01-31 10:00:00] <@alice> hello\n");
+        append(&dir, "random/2017/01/31.txt", "[2017-01-31 12:00:00] <alice> bye\n");
+        append(&dir, "2016/12/01.txt", "[2016-12-01 08:00:00] <bob> old\n");
+        let mut index = LogIndex::new(&dir);
+        index.refresh();
+
+        let seen = index.seen("~Alice").unwrap();
+        assert_eq!(seen.channel.as_ref().map(|s| s.as_str()), Some("random"));
+        assert_eq!(seen.line.text, "bye");
+        let seen = index.seen("bob").unwrap();
+        assert_eq!(seen.channel, None);
+        assert_eq!(seen.line.time, "2016-12-01 08:00:00");
+        assert!(index.seen("carol").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_out_the_lines_of_the_bot() {
+        let dir = temp_dir("ignore");
+        let log = "general/2017/01/31.txt";
+        append(&dir,
+               log,
+               "[2017-01-31 10:00:00] <alice> !grep cake\n\
+                [2017-01-31 10:00:01] <lucidbot> [2017-01-30 09:00:00] <bob> cake\n");
+        let mut index = LogIndex::new(&dir);
+        index.refresh();
+        index.ignore_user("lucidbot");
+        append(&dir, log, "[2017-01-31 10:00:02] <lucidbot> No matches.\n");
+        index.refresh();
+
+        assert_eq!(texts(&index, None, None, None, 10), vec!["!grep cake"]);
+        assert!(index.seen("lucidbot").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_public_streams_are_searched() {
+        for name in &["general", "random-2", "2024", "_general"] {
+            assert!(!logfile::is_private_stream(name), "{}", name);
+        }
+        for name in &[logfile::SYSTEM_STREAM, "@alice", "@#secret"] {
+            assert!(logfile::is_private_stream(name), "{}", name);
+        }
+    }
+}
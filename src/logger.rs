@@ -1,7 +1,111 @@
-use chrono::{Date, DateTime, Local, Timelike};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, Offset, Timelike, UTC};
+use dictionary::logfile;
+use std::fmt;
 use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub use dictionary::logfile::SYSTEM_STREAM;
+
+/// The source of the current time, so that the day rollover doesn't depend on the real one
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<UTC>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
+
+/// The time zone of the timestamps and the dates of the log files
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LogTimezone {
+    Local,
+    Utc,
+    /// A fixed offset from UTC, like `+02:00`
+    Fixed(FixedOffset),
+}
+
+impl LogTimezone {
+    pub fn convert(&self, time: DateTime<UTC>) -> DateTime<FixedOffset> {
+        match *self {
+            LogTimezone::Local => {
+                let local = time.with_timezone(&Local);
+                local.with_timezone(local.offset())
+            }
+            LogTimezone::Utc => time.with_timezone(&FixedOffset::east(0)),
+            LogTimezone::Fixed(offset) => time.with_timezone(&offset),
+        }
+    }
+}
+
+impl FromStr for LogTimezone {
+    type Err = String;
+
+    /// Parses `local`, `utc` or an offset like `+02:00` or `-0530`
+    fn from_str(text: &str) -> Result<LogTimezone, String> {
+        let error = || format!("{} is not local, utc or an offset like +02:00", text);
+        match text.to_lowercase().as_str() {
+            "local" => return Ok(LogTimezone::Local),
+            "utc" => return Ok(LogTimezone::Utc),
+            _ => (),
+        }
+        let sign = match text.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(error()),
+        };
+        let digits = text[1..].replace(':', "");
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_digit(10)) {
+            return Err(error());
+        }
+        let hours: i32 = digits[..2].parse().map_err(|_| error())?;
+        let minutes: i32 = digits[2..].parse().map_err(|_| error())?;
+        if minutes >= 60 {
+            return Err(error());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(LogTimezone::Fixed)
+            .ok_or_else(error)
+    }
+}
+
+impl fmt::Display for LogTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogTimezone::Local => write!(f, "local"),
+            LogTimezone::Utc => write!(f, "utc"),
+            LogTimezone::Fixed(offset) => {
+                let seconds = offset.local_minus_utc().num_seconds();
+                let sign = if seconds < 0 { '-' } else { '+' };
+                let minutes = seconds.abs() / 60;
+                write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+            }
+        }
+    }
+}
+
+/// When the logs move on to the next day's file
+#[derive(Clone, Copy)]
+pub struct Rollover {
+    /// The hour the new day's file starts at, if the bot wasn't idle
+    pub hour: u32,
+    /// After a pause in logging this long, the new day's file starts right away
+    pub idle: Duration,
+}
+
+impl Default for Rollover {
+    fn default() -> Rollover {
+        Rollover {
+            hour: 6,
+            idle: Duration::hours(4),
+        }
+    }
+}
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum LogMode {
@@ -10,67 +114,509 @@ pub enum LogMode {
     Both,
 }
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum RecordKind {
+    Message,
+    Edit,
+    Delete,
+    /// A note logged by a plugin
+    Note,
+    System,
+}
+
+impl RecordKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            RecordKind::Message => "message",
+            RecordKind::Edit => "edit",
+            RecordKind::Delete => "delete",
+            RecordKind::Note => "note",
+            RecordKind::System => "system",
+        }
+    }
+}
+
+/// A logged event; the fields which don't apply to it are left empty
+pub struct Record {
+    pub kind: RecordKind,
+    pub channel_id: Option<String>,
+    pub channel_name: Option<String>,
+    pub user_id: Option<String>,
+    pub user_name: Option<String>,
+    pub ts: Option<String>,
+    pub thread_ts: Option<String>,
+    pub text: Option<String>,
+}
+
+impl Record {
+    pub fn new(kind: RecordKind) -> Record {
+        Record {
+            kind: kind,
+            channel_id: None,
+            channel_name: None,
+            user_id: None,
+            user_name: None,
+            ts: None,
+            thread_ts: None,
+            text: None,
+        }
+    }
+
+    pub fn channel<S: AsRef<str>>(mut self, id: Option<S>, name: &str) -> Record {
+        self.channel_id = id.map(|id| id.as_ref().to_string());
+        self.channel_name = Some(name.to_string());
+        self
+    }
+
+    pub fn user(mut self, id: Option<&str>, name: &str) -> Record {
+        self.user_id = id.map(|id| id.to_string());
+        self.user_name = Some(name.to_string());
+        self
+    }
+
+    pub fn ts(mut self, ts: &str, thread_ts: Option<&str>) -> Record {
+        self.ts = Some(ts.to_string());
+        self.thread_ts = thread_ts.map(|ts| ts.to_string());
+        self
+    }
+
+    pub fn text<S: Into<String>>(mut self, text: S) -> Record {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// The line in the text log, if the event has one
+    fn text_line(&self) -> Option<String> {
+        let user = self.user_name.as_ref().map_or("", |s| s.as_str());
+        let text = self.text.as_ref().map_or("", |s| s.as_str());
+        match self.kind {
+            RecordKind::Message => Some(format!("<{}> {}", user, text)),
+            RecordKind::Edit => Some(format!("*** {} edited a message: {}", user, text)),
+            RecordKind::Delete => None,
+            RecordKind::Note | RecordKind::System => Some(text.to_string()),
+        }
+    }
+
+    fn to_json(&self, time: &DateTime<FixedOffset>) -> String {
+        json!({
+            "time": time.to_rfc3339(),
+            "event": self.kind.name(),
+            "channel_id": self.channel_id,
+            "channel": self.channel_name,
+            "user_id": self.user_id,
+            "user": self.user_name,
+            "ts": self.ts,
+            "thread_ts": self.thread_ts,
+            "text": self.text,
+        })
+                .to_string()
+    }
+}
+
+/// Writes the logs as `<base dir>/<channel>/YYYY/MM/DD.txt`, and everything that doesn't
+/// belong to a channel in the `SYSTEM_STREAM` directory. If enabled, every record is also
+/// written as a line of JSON to `DD.jsonl`, next to the text log.
+///
+/// Late night conversations stay in the previous day's file: the file only changes after
+/// midnight once the rollover hour comes or logging has been idle for a while.
 pub struct Logger {
     base_dir: PathBuf,
-    cur_date: Date<Local>,
-    last_log: DateTime<Local>,
+    clock: Box<Clock>,
+    timezone: LogTimezone,
+    rollover: Rollover,
+    cur_date: NaiveDate,
+    last_log: DateTime<UTC>,
     day_passed: bool,
+    /// Whether a file has been written to yet
+    written: bool,
+    json: bool,
+}
+
+/// A channel name usable as a directory name
+fn stream_dir(channel: &str) -> String {
+    let name: String = channel
+        .chars()
+        .map(|c| match c {
+                 '/' | '\\' | ':' | '~' => '_',
+                 c if c.is_control() => '_',
+                 c => c,
+             })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        format!("_{}", name)
+    } else {
+        name
+    }
 }
 
 impl Logger {
-    fn gen_path(&self, date: &Date<Local>) -> io::Result<PathBuf> {
+    fn gen_path(&self, stream: &str, date: &NaiveDate, ext: &str) -> io::Result<PathBuf> {
         let base_dir = self.base_dir.as_path();
         let year_str = format!("{}", date.format("%Y"));
         let month_str = format!("{}", date.format("%m"));
         let day_str = format!("{}", date.format("%d"));
 
-        let path = base_dir.join(year_str).join(month_str);
+        let path = base_dir.join(stream).join(year_str).join(month_str);
         fs::create_dir_all(&path)?;
-        Ok(path.join(format!("{}.txt", day_str)))
+        Ok(path.join(format!("{}.{}", day_str, ext)))
     }
 
     pub fn new<P: AsRef<Path>>(path: P) -> Logger {
+        Logger::with_clock(path, Box::new(SystemClock))
+    }
+
+    pub fn with_clock<P: AsRef<Path>>(path: P, clock: Box<Clock>) -> Logger {
+        let now = clock.now();
+        let timezone = LogTimezone::Local;
         Logger {
             base_dir: path.as_ref().to_path_buf(),
-            cur_date: Local::today(),
-            last_log: Local::now(),
+            clock: clock,
+            timezone: timezone,
+            rollover: Rollover::default(),
+            cur_date: timezone.convert(now).date().naive_local(),
+            last_log: now,
             day_passed: false,
+            written: false,
+            json: false,
         }
     }
 
-    pub fn log_with_mode<P: AsRef<str>>(&mut self, what: P, mode: LogMode) -> io::Result<()> {
-        let now = Local::now();
+    /// The current date in the time zone of the logs
+    pub fn today(&self) -> NaiveDate {
+        self.timezone.convert(self.clock.now()).date().naive_local()
+    }
+
+    /// Changes the time zone; once something was logged, the current file is kept until the
+    /// next rollover
+    pub fn set_timezone(&mut self, timezone: LogTimezone) {
+        self.timezone = timezone;
+        if !self.written {
+            self.cur_date = self.today();
+        }
+    }
+
+    pub fn set_rollover(&mut self, rollover: Rollover) {
+        self.rollover = rollover;
+    }
+
+    /// Enables or disables the JSON-lines logs
+    pub fn set_json(&mut self, json: bool) {
+        self.json = json;
+    }
+
+    fn append(&self, stream: &str, ext: &str, line: &str) -> io::Result<()> {
+        let path = self.gen_path(stream, &self.cur_date, ext)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(format!("{}\n", line).as_bytes())
+    }
+
+    fn write(&mut self, record: &Record, mode: LogMode) -> io::Result<()> {
+        let utc_now = self.clock.now();
+        let now = self.timezone.convert(utc_now);
+        let last_log = self.timezone.convert(self.last_log);
         let now_str = now.format("%Y-%m-%d %H:%M:%S");
-        let time_diff = now - self.last_log;
+        let time_diff = utc_now - self.last_log;
+        let stream = record
+            .channel_name
+            .as_ref()
+            .map_or(SYSTEM_STREAM.to_string(), |channel| stream_dir(channel));
+        let line = record.text_line();
 
         if mode == LogMode::Console || mode == LogMode::Both {
-            println!("[{}] {}", now_str, what.as_ref());
+            if let Some(ref line) = line {
+                if stream == SYSTEM_STREAM {
+                    println!("[{}] {}", now_str, line);
+                } else {
+                    println!("[{}] #{} {}", now_str, stream, line);
+                }
+            }
         }
 
         if mode == LogMode::File || mode == LogMode::Both {
-            if now.date() > self.last_log.date() {
+            if now.date().naive_local() > last_log.date().naive_local() {
                 self.day_passed = true;
             }
 
-            if self.day_passed && (time_diff.num_seconds() > 14400 || now.hour() >= 6) {
-                self.cur_date = now.date();
+            if self.day_passed &&
+               (time_diff > self.rollover.idle || now.hour() >= self.rollover.hour) {
+                self.cur_date = now.date().naive_local();
                 self.day_passed = false;
             }
 
-            let path = self.gen_path(&self.cur_date)?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)?;
-            let what = format!("[{}] {}\n", now_str, what.as_ref());
-            file.write_all(what.as_bytes())?;
-            self.last_log = now;
+            if let Some(ref line) = line {
+                self.append(&stream, "txt", &format!("[{}] {}", now_str, line))?;
+            }
+            if self.json {
+                self.append(&stream, "jsonl", &record.to_json(&now))?;
+            }
+            self.last_log = utc_now;
+            self.written = true;
         }
 
         Ok(())
     }
 
+    /// Logs a core or system event
+    pub fn log_with_mode<P: AsRef<str>>(&mut self, what: P, mode: LogMode) -> io::Result<()> {
+        self.write(&Record::new(RecordKind::System).text(what.as_ref()), mode)
+    }
+
     pub fn log<P: AsRef<str>>(&mut self, what: P) -> io::Result<()> {
         self.log_with_mode(what, LogMode::Both)
     }
+
+    /// Logs an event, in the log of its channel if it has one
+    pub fn log_record(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record, LogMode::Both)
+    }
+}
+
+/// How many days the logs are kept as they are and at all; 0 means forever
+#[derive(Clone, Copy)]
+pub struct Retention {
+    pub compress_after: u64,
+    pub delete_after: u64,
+}
+
+#[derive(Default)]
+pub struct RetentionSummary {
+    pub compressed: usize,
+    pub deleted: usize,
+    pub errors: Vec<String>,
+}
+
+/// The date of a log file, from its `YYYY/MM/DD.<ext>` path
+pub fn log_date(path: &Path) -> Option<NaiveDate> {
+    let path = logfile::uncompressed_path(path);
+    let day = path.file_stem()?.to_str()?.parse().ok()?;
+    let month_dir = path.parent()?;
+    let month = month_dir.file_name()?.to_str()?.parse().ok()?;
+    let year = month_dir.parent()?.file_name()?.to_str()?.parse().ok()?;
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+fn apply_retention_in(dir: &Path,
+                      retention: Retention,
+                      today: NaiveDate,
+                      summary: &mut RetentionSummary) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            summary.errors.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if path.is_dir() {
+            let deleted = summary.deleted;
+            apply_retention_in(&path, retention, today, summary);
+            if summary.deleted > deleted {
+                // fails unless the directory is empty now
+                let _ = fs::remove_dir(&path);
+            }
+            continue;
+        }
+        let age = match log_date(&path) {
+            Some(date) => (today - date).num_days(),
+            None => continue,
+        };
+        let result = if retention.delete_after > 0 && age > retention.delete_after as i64 {
+            fs::remove_file(&path).map(|_| summary.deleted += 1)
+        } else if retention.compress_after > 0 && age > retention.compress_after as i64 &&
+                  !logfile::is_compressed(&path) {
+            logfile::compress(&path).map(|_| summary.compressed += 1)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            summary.errors.push(format!("{}: {}", path.display(), e));
+        }
+    }
+}
+
+/// Compresses and deletes old logs in all streams under the base directory; the ages of
+/// the logs are counted from `today`
+pub fn apply_retention<P: AsRef<Path>>(base_dir: P,
+                                       retention: Retention,
+                                       today: NaiveDate)
+                                       -> RetentionSummary {
+    let mut summary = RetentionSummary::default();
+    let base_dir = base_dir.as_ref();
+    if base_dir.exists() {
+        apply_retention_in(base_dir, retention, today, &mut summary);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct FakeClock(Arc<Mutex<DateTime<UTC>>>);
+
+    impl FakeClock {
+        fn new(time: DateTime<UTC>) -> FakeClock {
+            FakeClock(Arc::new(Mutex::new(time)))
+        }
+
+        fn set(&self, time: DateTime<UTC>) {
+            *self.0.lock().unwrap() = time;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<UTC> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<UTC> {
+        UTC.ymd(2026, 3, day).and_hms(hour, minute, 0)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lucidbot-logger-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// A logger set up like the bot's, after starting in the local time zone
+    fn logger(dir: &Path, clock: &FakeClock, timezone: LogTimezone) -> Logger {
+        let mut logger = Logger::with_clock(dir, Box::new(clock.clone()));
+        logger.set_timezone(timezone);
+        logger
+    }
+
+    fn log(logger: &mut Logger, text: &str) {
+        let record = Record::new(RecordKind::Message)
+            .channel(None::<&str>, "general")
+            .user(None, "alice")
+            .text(text);
+        logger.write(&record, LogMode::File).unwrap();
+    }
+
+    /// The lines of the channel's log of a day in March
+    fn lines(dir: &Path, day: u32) -> Vec<String> {
+        let path = dir.join("general")
+            .join("2026")
+            .join("03")
+            .join(format!("{:02}.txt", day));
+        fs::read_to_string(path)
+            .map(|text| text.lines().map(|line| line.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn stays_in_the_previous_day_before_the_rollover_hour() {
+        let dir = temp_dir("before");
+        let clock = FakeClock::new(utc(10, 23, 0));
+        let mut logger = logger(&dir, &clock, LogTimezone::Utc);
+        log(&mut logger, "late");
+        clock.set(utc(11, 1, 0));
+        log(&mut logger, "after midnight");
+        clock.set(utc(11, 4, 30));
+        log(&mut logger, "still up");
+
+        assert_eq!(lines(&dir, 10),
+                   vec!["[2026-03-10 23:00:00] <alice> late",
+                        "[2026-03-11 01:00:00] <alice> after midnight",
+                        "[2026-03-11 04:30:00] <alice> still up"]);
+        assert!(lines(&dir, 11).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn moves_to_the_new_day_at_the_rollover_hour() {
+        let dir = temp_dir("hour");
+        let clock = FakeClock::new(utc(10, 23, 0));
+        let mut logger = logger(&dir, &clock, LogTimezone::Utc);
+        log(&mut logger, "late");
+        clock.set(utc(11, 2, 0));
+        log(&mut logger, "later");
+        clock.set(utc(11, 5, 59));
+        log(&mut logger, "dawn");
+        clock.set(utc(11, 6, 0));
+        log(&mut logger, "morning");
+        clock.set(utc(11, 7, 0));
+        log(&mut logger, "breakfast");
+
+        assert_eq!(lines(&dir, 10).len(), 3);
+        assert_eq!(lines(&dir, 11),
+                   vec!["[2026-03-11 06:00:00] <alice> morning",
+                        "[2026-03-11 07:00:00] <alice> breakfast"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn moves_to_the_new_day_after_an_idle_gap() {
+        let dir = temp_dir("idle");
+        let clock = FakeClock::new(utc(10, 23, 0));
+        let mut logger = logger(&dir, &clock, LogTimezone::Utc);
+        log(&mut logger, "late");
+        clock.set(utc(11, 3, 30));
+        log(&mut logger, "early");
+
+        assert_eq!(lines(&dir, 10), vec!["[2026-03-10 23:00:00] <alice> late"]);
+        assert_eq!(lines(&dir, 11), vec!["[2026-03-11 03:30:00] <alice> early"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dates_the_files_in_the_time_zone() {
+        let dir = temp_dir("utc");
+        let clock = FakeClock::new(utc(10, 22, 30));
+        log(&mut logger(&dir, &clock, LogTimezone::Utc), "utc");
+        assert_eq!(lines(&dir, 10), vec!["[2026-03-10 22:30:00] <alice> utc"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let dir = temp_dir("east");
+        let east = "+02:00".parse().unwrap();
+        log(&mut logger(&dir, &clock, east), "east");
+        assert_eq!(lines(&dir, 11), vec!["[2026-03-11 00:30:00] <alice> east"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let dir = temp_dir("west");
+        clock.set(utc(11, 3, 0));
+        let west = "-05:00".parse().unwrap();
+        log(&mut logger(&dir, &clock, west), "west");
+        assert_eq!(lines(&dir, 10), vec!["[2026-03-10 22:00:00] <alice> west"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_the_file_when_the_time_zone_changes_later() {
+        let dir = temp_dir("change");
+        let clock = FakeClock::new(utc(10, 22, 30));
+        let mut logger = logger(&dir, &clock, LogTimezone::Utc);
+        log(&mut logger, "before");
+        logger.set_timezone("+02:00".parse().unwrap());
+        log(&mut logger, "after");
+
+        assert_eq!(lines(&dir, 10),
+                   vec!["[2026-03-10 22:30:00] <alice> before",
+                        "[2026-03-11 00:30:00] <alice> after"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn time_zones_read_back_as_written() {
+        for text in &["local", "utc", "+02:00", "-05:30", "+00:00", "+14:00"] {
+            let timezone: LogTimezone = text.parse().unwrap();
+            assert_eq!(timezone.to_string(), *text);
+            assert_eq!(timezone.to_string().parse::<LogTimezone>(), Ok(timezone));
+        }
+        assert_eq!("UTC".parse::<LogTimezone>(), Ok(LogTimezone::Utc));
+        assert_eq!("-0530".parse::<LogTimezone>().unwrap().to_string(), "-05:30");
+        for text in &["", "CET", "+2", "02:00", "+02:60", "+0a:00", "+24:00"] {
+            assert!(text.parse::<LogTimezone>().is_err(), "{}", text);
+        }
+    }
 }
@@ -7,20 +7,48 @@ extern crate timer;
 extern crate chrono;
 extern crate dictionary;
 extern crate rand;
+extern crate toml;
+extern crate getopts;
+extern crate libc;
+#[macro_use]
+extern crate serde_json;
 
+mod backend;
+mod channel_filter;
+mod command;
 mod settings;
 mod plugin;
 mod plugins;
 mod logger;
+mod log_index;
+mod permissions;
+mod queue;
+mod scheduler;
+mod shutdown;
+mod worker;
 
-use logger::Logger;
+use backend::{BackendError, Channel, ChannelKind, ChatBackend, ConsoleBackend, Event,
+              EventHandler, EventKind, SlackBackend, User};
+use channel_filter::ChannelFilter;
+use command::{ArgSpec, Command, CommandError, CommandOwner, CommandRegistry, CommandSpec};
+use getopts::Options;
+use logger::{LogMode, Logger, Record, RecordKind, Retention, RetentionSummary, Rollover};
+use permissions::Role;
 use plugin::Plugin;
 use plugins::*;
-use settings::SETTINGS;
-use slack::{Event, EventHandler, Message, RtmClient};
-use slack_api::MessageStandard;
-use std::collections::HashMap;
+use queue::EventQueue;
+use scheduler::{DueJob, Job, JobHandle, Scheduler, Timing};
+use settings::{ConfigError, Overrides, SETTINGS, Settings, SettingsHandle};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use worker::PluginWorker;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum ResumeEventHandling {
@@ -28,10 +56,29 @@ pub enum ResumeEventHandling {
     Stop,
 }
 
+#[derive(Clone)]
+pub enum BotAction {
+    /// Send a message to the channel the event came from
+    Send(String),
+    /// Send a message to a channel, given by name or ID
+    SendTo(String, String),
+    /// Send a direct message to a user, given by name or ID
+    SendDirect(String, String),
+    /// Reply in the thread of the message the event came from
+    ReplyInThread(String),
+    /// Add an emoji reaction to the message the event came from
+    React(String),
+    Log(String),
+    /// Run the job at the given time, replacing any job scheduled with the same handle
+    Schedule(JobHandle, Timing, Job),
+    Cancel(JobHandle),
+}
+
 pub enum BotEvent {
     None(ResumeEventHandling),
     Log(String, ResumeEventHandling),
     Send(String, ResumeEventHandling),
+    Actions(Vec<BotAction>, ResumeEventHandling),
 }
 
 impl BotEvent {
@@ -39,7 +86,17 @@ impl BotEvent {
         match *self {
             BotEvent::None(r) |
             BotEvent::Log(_, r) |
-            BotEvent::Send(_, r) => r,
+            BotEvent::Send(_, r) |
+            BotEvent::Actions(_, r) => r,
+        }
+    }
+
+    pub fn into_actions(self) -> Vec<BotAction> {
+        match self {
+            BotEvent::None(_) => vec![],
+            BotEvent::Log(message, _) => vec![BotAction::Log(message)],
+            BotEvent::Send(message, _) => vec![BotAction::Send(message)],
+            BotEvent::Actions(actions, _) => actions,
         }
     }
 }
@@ -52,164 +109,1249 @@ pub struct MessageData<'a> {
     pub msg: &'a str,
 }
 
+/// The message that triggered the current actions
+#[derive(Clone)]
+pub struct Origin {
+    channel: String,
+    ts: String,
+    thread_ts: Option<String>,
+}
+
 struct BotCore {
-    plugins: Vec<Box<Plugin>>,
+    plugins: Vec<PluginWorker>,
+    /// Channel filters of the plugins, in the same order as `plugins`
+    filters: Vec<ChannelFilter>,
+    commands: CommandRegistry,
+    /// User names with role prefixes, by ID
     users: HashMap<String, String>,
+    members: HashMap<String, User>,
+    /// IDs of the users listed in the `admins` setting
+    admins: HashSet<String>,
     channels: HashMap<String, String>,
     logger: Logger,
+    tick_interval: Duration,
+    last_tick: Instant,
+    /// Notes about plugin results which arrived after their time budget
+    late_results: Receiver<String>,
+    scheduler: Scheduler,
+    /// Last modification times of the settings and plugin data files, if watched
+    file_times: HashMap<PathBuf, Option<SystemTime>>,
+    log_dir: PathBuf,
+    maintenance: JobHandle,
+    /// The result of the log retention running in the background, if it is running
+    retention: Option<Receiver<RetentionSummary>>,
 }
 
 impl BotCore {
-    fn new() -> BotCore {
-        let mut plugins: Vec<Box<Plugin>> = Vec::new();
+    fn new(queue: EventQueue, plugins: Vec<Box<Plugin>>) -> BotCore {
+        let filters = plugins
+            .iter()
+            .map(|plugin| ChannelFilter::load(plugin.plugin_name()))
+            .collect();
 
-        // load all used plugins
-        plugins.push(Box::new(Patterns::new().unwrap()));
-        plugins.push(Box::new(Eightball::new().unwrap()));
-        plugins.push(Box::new(RandomChat::new()));
+        let (late_tx, late_rx) = mpsc::channel();
+        let workers = {
+            let settings = SETTINGS.lock().unwrap();
+            plugins
+                .into_iter()
+                .map(|plugin| {
+                    let budget = settings
+                        .plugin(plugin.plugin_name())
+                        .time_budget
+                        .unwrap_or(settings.core.plugin_time_budget);
+                    PluginWorker::spawn(plugin, Duration::from_millis(budget), late_tx.clone())
+                })
+                .collect()
+        };
 
-        let log_dir = env::current_dir().unwrap().as_path().join("logs");
+        let log_dir = SETTINGS.lock().unwrap().data_path("logs");
+        let tick_interval = SETTINGS.lock().unwrap().core.tick_interval;
 
-        BotCore {
-            plugins: plugins,
+        let mut core = BotCore {
+            plugins: workers,
+            filters: filters,
+            commands: CommandRegistry::new(),
             users: HashMap::new(),
+            members: HashMap::new(),
+            admins: HashSet::new(),
             channels: HashMap::new(),
-            logger: Logger::new(log_dir),
+            logger: Logger::new(&log_dir),
+            tick_interval: Duration::from_secs(tick_interval),
+            last_tick: Instant::now(),
+            late_results: late_rx,
+            scheduler: Scheduler::new(queue),
+            file_times: HashMap::new(),
+            log_dir: log_dir,
+            maintenance: JobHandle::new(),
+            retention: None,
+        };
+        core.configure_logger();
+        core.scheduler
+            .schedule(core.maintenance,
+                      Timing::Every(chrono::Duration::days(1)),
+                      Job::Maintenance,
+                      None,
+                      None);
+        core.run_maintenance();
+        core.register_commands();
+        core.update_file_times();
+        core
+    }
+
+    fn register_commands(&mut self) {
+        let mut specs = vec![(CommandOwner::Core,
+                              CommandSpec::new("help", "Lists commands or describes one of them")
+                                  .arg(ArgSpec::Optional("command"))),
+                             (CommandOwner::Core,
+                              CommandSpec::new("plugin",
+                                               "Lists plugins or their settings, or enables \
+                                                and disables them in a channel (the current \
+                                                one by default)")
+                                  .arg(ArgSpec::Required("list|settings|allow|deny|reset"))
+                                  .arg(ArgSpec::Optional("plugin"))
+                                  .arg(ArgSpec::Optional("channel"))
+                                  .role(Role::Admin)),
+                             (CommandOwner::Core,
+                              CommandSpec::new("config", "Lists, shows or changes settings")
+                                  .arg(ArgSpec::Required("list|get|set"))
+                                  .arg(ArgSpec::Optional("setting"))
+                                  .arg(ArgSpec::Rest("value"))
+                                  .role(Role::Admin)),
+                             (CommandOwner::Core,
+                              CommandSpec::new("reload",
+                                               "Reloads the settings and the data of all plugins")
+                                  .role(Role::Admin))];
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            specs.extend(plugin
+                             .commands()
+                             .iter()
+                             .map(|spec| (CommandOwner::Plugin(i), spec.clone())));
+        }
+
+        for (owner, spec) in specs {
+            let name = spec.name;
+            for conflict in self.commands.register(owner, spec) {
+                let _ = self.logger
+                    .log(format!("***WARNING: Command name {} of {} is already taken",
+                                 conflict,
+                                 name));
+            }
         }
     }
 
-    pub fn handle_message(&mut self, client: &RtmClient, user: &str, channel: &str, msg: &str) {
-        let resp = client.start_response();
-        let user_name = if let Some(name) = self.users.get(user) {
-            &name
+    fn handle_core_command(&mut self,
+                           command: &Command,
+                           command_char: &str,
+                           user_name: &str,
+                           channel_name: &str)
+                           -> BotEvent {
+        let response = match command.name.as_ref() {
+            "help" => self.help(command.args.get(0), command_char),
+            "config" => self.configure(command, user_name),
+            "plugin" => self.configure_plugin(&command.args, channel_name),
+            "reload" => self.reload_all(),
+            _ => return BotEvent::None(ResumeEventHandling::Resume),
+        };
+        BotEvent::Send(response, ResumeEventHandling::Stop)
+    }
+
+    fn help(&self, name: Option<&String>, command_char: &str) -> String {
+        if let Some(name) = name {
+            let name = name.trim_left_matches(command_char);
+            if let Some(&(_, ref spec)) = self.commands.find(name) {
+                format!("{} - {}", spec.usage(command_char), spec.help)
+            } else {
+                format!("{}", CommandError::UnknownCommand(name.to_string()))
+            }
         } else {
-            user
+            let names = self.commands
+                .specs()
+                .iter()
+                .map(|spec| format!("{}{}", command_char, spec.name))
+                .collect::<Vec<_>>();
+            format!("Available commands: {}", names.join(", "))
+        }
+    }
+
+    fn configure(&mut self, command: &Command, user_name: &str) -> String {
+        let args = &command.args;
+        let action = args[0].to_lowercase();
+        if action == "list" {
+            return SETTINGS.lock().unwrap().list().join("\n");
+        }
+
+        let key = match args.get(1) {
+            Some(key) => key.to_lowercase(),
+            None => return "Setting name missing".to_string(),
+        };
+        match action.as_ref() {
+            "get" => {
+                SETTINGS
+                    .lock()
+                    .unwrap()
+                    .describe(&key)
+                    .unwrap_or_else(|e| e)
+            }
+            "set" => {
+                if args.len() < 3 {
+                    return "Value missing".to_string();
+                }
+                // a single value can be quoted, the rest of the text keeps its spacing
+                let value = if args.len() == 3 {
+                    &args[2]
+                } else {
+                    command.raw_after(2)
+                };
+                let result = SETTINGS.lock().unwrap().set(&key, value);
+                match result {
+                    Ok((old, new)) => {
+                        let _ = self.logger
+                            .log(format!("*** {} set {} to {} (was {}) ***",
+                                         user_name,
+                                         key,
+                                         new,
+                                         old));
+                        self.apply_settings();
+                        format!("{} = {}", key, new)
+                    }
+                    Err(e) => e,
+                }
+            }
+            _ => format!("Unknown parameter value: {}", args[0]),
+        }
+    }
+
+    fn configure_plugin(&mut self, args: &[String], channel_name: &str) -> String {
+        let action = args[0].to_lowercase();
+        if action == "list" {
+            return self.filters
+                       .iter()
+                       .map(|filter| filter.describe())
+                       .collect::<Vec<_>>()
+                       .join("\n");
+        }
+        if action == "settings" {
+            let settings = SETTINGS.lock().unwrap();
+            let plugins = match args.get(1) {
+                Some(name) => vec![name.to_lowercase()],
+                None => {
+                    settings
+                        .plugins_with_settings()
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect()
+                }
+            };
+            let lines: Vec<_> = plugins
+                .iter()
+                .flat_map(|name| settings.describe_plugin(name))
+                .collect();
+            return if lines.is_empty() {
+                       "No plugin settings".to_string()
+                   } else {
+                       lines.join("\n")
+                   };
+        }
+
+        let plugin_name = match args.get(1) {
+            Some(name) => name.to_lowercase(),
+            None => return "Plugin name missing".to_string(),
+        };
+        let index = match self.plugins
+                  .iter()
+                  .position(|plugin| plugin.name() == plugin_name) {
+            Some(index) => index,
+            None => return format!("Unknown plugin: {}", plugin_name),
         };
-        let channel_name = if let Some(name) = self.channels.get(channel) {
-            &name
+        let channel = args.get(2).map(|c| c.as_str());
+        let filter = &mut self.filters[index];
+
+        let result = match action.as_ref() {
+            "allow" => filter.allow(channel.unwrap_or(channel_name)),
+            "deny" => filter.deny(channel.unwrap_or(channel_name)),
+            "reset" => filter.reset(channel),
+            _ => return format!("Unknown parameter value: {}", args[0]),
+        };
+        match result {
+            Ok(()) => filter.describe(),
+            Err(e) => e,
+        }
+    }
+
+    pub fn handle_message(&mut self,
+                          backend: &ChatBackend,
+                          user: &str,
+                          channel: &str,
+                          msg: &str,
+                          ts: &str,
+                          thread_ts: Option<&str>) {
+        let user_name = self.users
+            .get(user)
+            .cloned()
+            .unwrap_or_else(|| user.to_string());
+        let channel_name = self.message_channel_name(backend, channel, user);
+        let command_char = SETTINGS.lock().unwrap().core.command_char.clone();
+        let parsed = if msg.starts_with(&command_char) {
+            Some(self.commands.parse(&msg[command_char.len()..], &command_char))
         } else {
-            channel
+            None
         };
 
-        let _ = self.logger.log(format!("<{}> {}", user_name, msg));
-        let self_name = resp.slf
-            .as_ref()
-            .and_then(|u| u.name.as_ref())
-            .unwrap();
+        let logged = match parsed {
+            Some(Ok((_, ref command))) => redact_command(command, &command_char),
+            _ => None,
+        };
+        let _ = self.logger
+            .log_record(&Record::new(RecordKind::Message)
+                             .channel(Some(channel), &channel_name)
+                             .user(Some(user), &user_name)
+                             .ts(ts, thread_ts)
+                             .text(logged.as_ref().map_or(msg, |text| text.as_str())));
+        let self_name = match backend.self_user() {
+            Some(user) => user.name,
+            None => {
+                let _ = self.logger
+                    .log("***WARNING: Not handling the message, the bot's own user is unknown");
+                return;
+            }
+        };
         let msg_data = MessageData {
-            self_name: self_name,
-            user: user_name,
-            channel: channel_name,
+            self_name: &self_name,
+            user: &user_name,
+            channel: &channel_name,
             msg: msg,
         };
 
-        self.plugins
-            .sort_by_key(|x| x.plugin_priority(user, channel, msg));
-
-        for plugin in &mut self.plugins {
-            // detect if a command
-            let command_char = SETTINGS.lock().unwrap().command_char.clone();
-
-            // pass to the plugin
-            let result = if msg.starts_with(&command_char) {
-                let params = msg[command_char.len()..]
-                    .split_whitespace()
-                    .map(|x| x.to_lowercase())
-                    .collect();
-                plugin.handle_command(user_name, channel_name, params)
-            } else {
-                plugin.handle_message(msg_data)
-            };
-
-            // perform the action requested by the plugin
-            let resume = result.resume_mode();
-            match result {
-                BotEvent::Log(message, _) => {
-                    let _ = self.logger.log(message);
+        // actions paired with the index of the plugin which requested them
+        let actions: Vec<(Option<usize>, BotAction)> = if let Some(parsed) = parsed {
+            let parsed = parsed.and_then(|(owner, command)| {
+                                             self.check_role(user,
+                                                             &user_name,
+                                                             &command,
+                                                             &command_char)
+                                                 .map(|_| (owner, command))
+                                         });
+            let (owner, actions) = match parsed {
+                Ok((CommandOwner::Core, command)) => {
+                    (None,
+                     self.handle_core_command(&command, &command_char, &user_name, &channel_name)
+                         .into_actions())
+                }
+                Ok((CommandOwner::Plugin(i), command)) => {
+                    (Some(i),
+                     self.run_plugin_command(i,
+                                             command,
+                                             &user_name,
+                                             channel,
+                                             &channel_name,
+                                             &command_char))
                 }
-                BotEvent::Send(message, _) => {
-                    let sender = client.sender();
-                    if let Err(e) = sender.send_message(channel, &message) {
+                Err(CommandError::Empty) => (None, vec![]),
+                Err(e @ CommandError::NotAllowed(..)) => {
+                    // only the name, the arguments may hold a secret
+                    if let CommandError::NotAllowed(_, ref name, _) = e {
                         let _ = self.logger
-                            .log(format!("***ERROR: Couldn't send message: {:?}", e));
-                    } else {
-                        let _ = self.logger.log(format!("<{}> {}", self_name, &message));
+                            .log(format!("***WARNING: {} tried to use {} without permission",
+                                         user_name,
+                                         name));
+                    }
+                    (None, vec![BotAction::Send(format!("{}", e))])
+                }
+                // text like "!!!" or "!important" isn't meant as a command, so only likely
+                // typos are answered
+                Err(CommandError::UnknownCommand(name)) => {
+                    match self.commands.suggest(&name) {
+                        Some(known) => {
+                            (None,
+                             vec![BotAction::Send(format!("Unknown command: {}. Did you mean \
+                                                           {}{}?",
+                                                          name,
+                                                          command_char,
+                                                          known))])
+                        }
+                        None => (None, vec![]),
                     }
                 }
-                BotEvent::None(_) => (),
+                Err(e) => (None, vec![BotAction::Send(format!("{}", e))]),
+            };
+            actions.into_iter().map(|action| (owner, action)).collect()
+        } else {
+            self.run_plugins(msg_data, user, channel, &channel_name)
+        };
+
+        // perform the actions requested by the plugins
+        let origin = Origin {
+            channel: channel.to_string(),
+            ts: ts.to_string(),
+            thread_ts: thread_ts.map(|s| s.to_string()),
+        };
+        for (owner, action) in actions {
+            self.perform_action(backend, owner, Some(&origin), action);
+        }
+    }
+
+    fn role_of(&self, user: &str) -> Role {
+        self.members
+            .get(user)
+            .map(|user| Role::of(user, &self.admins))
+            .unwrap_or(Role::User)
+    }
+
+    fn check_role(&self,
+                  user: &str,
+                  user_name: &str,
+                  command: &Command,
+                  command_char: &str)
+                  -> Result<(), CommandError> {
+        let required = self.commands
+            .find(&command.name)
+            .map(|&(_, ref spec)| spec.role)
+            .unwrap_or(Role::User);
+        if self.role_of(user) >= required {
+            Ok(())
+        } else {
+            Err(CommandError::NotAllowed(user_name.to_string(),
+                                         format!("{}{}", command_char, command.name),
+                                         required))
+        }
+    }
+
+    fn run_plugin_command(&mut self,
+                          i: usize,
+                          command: Command,
+                          user_name: &str,
+                          channel: &str,
+                          channel_name: &str,
+                          command_char: &str)
+                          -> Vec<BotAction> {
+        if !self.filters[i].is_enabled(channel, channel_name) {
+            return vec![BotAction::Send(format!("{}{} is disabled in this channel.",
+                                                command_char,
+                                                command.name))];
+        }
+
+        let name = command.name.clone();
+        match self.plugins[i].handle_command(user_name, channel_name, command) {
+            Ok(result) => result.into_actions(),
+            Err(e) => {
+                let _ = self.logger
+                    .log(format!("***WARNING: Plugin {} {} (command {})",
+                                 self.plugins[i].name(),
+                                 e,
+                                 name));
+                vec![]
+            }
+        }
+    }
+
+    /// Passes a message to the plugins enabled in the channel, in order of their priority
+    fn run_plugins(&mut self,
+                   msg_data: MessageData,
+                   user: &str,
+                   channel: &str,
+                   channel_name: &str)
+                   -> Vec<(Option<usize>, BotAction)> {
+        let mut order = Vec::new();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            if !self.filters[i].is_enabled(channel, channel_name) {
+                continue;
+            }
+            match plugin.priority(user, channel, msg_data.msg) {
+                Ok(priority) => order.push((priority, i)),
+                Err(e) => {
+                    let _ = self.logger
+                        .log(format!("***WARNING: Plugin {} {} (priority)", plugin.name(), e));
+                }
             }
+        }
+        order.sort_by_key(|&(priority, _)| priority);
+
+        let mut actions = Vec::new();
+        for (_, i) in order {
+            let result = match self.plugins[i].handle_message(msg_data) {
+                Ok(result) => result,
+                Err(e) => {
+                    let _ = self.logger
+                        .log(format!("***WARNING: Plugin {} {} (message)",
+                                     self.plugins[i].name(),
+                                     e));
+                    continue;
+                }
+            };
+            let resume = result.resume_mode();
+            actions.extend(result.into_actions().into_iter().map(|action| (Some(i), action)));
 
             if resume == ResumeEventHandling::Stop {
                 break;
             }
         }
+        actions
+    }
+
+    /// Runs a job which is due, unless it has been cancelled in the meantime
+    pub fn on_job_due(&mut self, backend: &ChatBackend, handle: JobHandle) {
+        let DueJob { owner, origin, job } = match self.scheduler.take_due(handle) {
+            Some(due) => due,
+            None => return,
+        };
+
+        let actions = match job {
+            Job::Actions(actions) => actions,
+            Job::Maintenance => {
+                self.run_maintenance();
+                return;
+            }
+            Job::Callback => {
+                let plugin = match owner {
+                    Some(i) => &self.plugins[i],
+                    None => return,
+                };
+                match plugin.on_scheduled(handle) {
+                    Ok(result) => result.into_actions(),
+                    Err(e) => {
+                        let _ = self.logger
+                            .log(format!("***WARNING: Plugin {} {} (scheduled job)",
+                                         plugin.name(),
+                                         e));
+                        return;
+                    }
+                }
+            }
+        };
+
+        for action in actions {
+            self.perform_action(backend, owner, origin.as_ref(), action);
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.last_tick.elapsed() < self.tick_interval {
+            return;
+        }
+        self.last_tick = Instant::now();
+        for plugin in &self.plugins {
+            if let Err(e) = plugin.on_tick() {
+                let _ = self.logger
+                    .log(format!("***WARNING: Plugin {} {} (tick)", plugin.name(), e));
+            }
+        }
+    }
+
+    fn update_user(&mut self, user: &User) {
+        let role = Role::of(user, &self.admins);
+        self.users
+            .insert(user.id.clone(), format!("{}{}", role.prefix(), &user.name));
+        self.members.insert(user.id.clone(), user.clone());
+    }
+
+    /// Passes the event to the plugins subscribed to it
+    fn dispatch_event(&mut self, backend: &ChatBackend, kind: EventKind, event: Event) {
+        let origin = match event {
+            Event::MessageChanged {
+                ref channel,
+                ref ts,
+                ..
+            } |
+            Event::MessageDeleted {
+                ref channel,
+                ref ts,
+            } => {
+                Some(Origin {
+                         channel: channel.clone(),
+                         ts: ts.clone(),
+                         thread_ts: None,
+                     })
+            }
+            _ => None,
+        };
+        let channel = event
+            .channel()
+            .map(|id| {
+                     let name = self.channels.get(id).cloned().unwrap_or_else(|| id.to_string());
+                     (id.to_string(), name)
+                 });
+
+        let mut actions = Vec::new();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            if !plugin.is_subscribed(kind) {
+                continue;
+            }
+            if let Some((ref id, ref name)) = channel {
+                if !self.filters[i].is_enabled(id, name) {
+                    continue;
+                }
+            }
+
+            match plugin.handle_event(event.clone()) {
+                Ok(result) => {
+                    let resume = result.resume_mode();
+                    actions.extend(result
+                                       .into_actions()
+                                       .into_iter()
+                                       .map(|action| (Some(i), action)));
+                    if resume == ResumeEventHandling::Stop {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = self.logger
+                        .log(format!("***WARNING: Plugin {} {} (event)", plugin.name(), e));
+                }
+            }
+        }
+
+        for (owner, action) in actions {
+            self.perform_action(backend, owner, origin.as_ref(), action);
+        }
+    }
+
+    fn log_late_results(&mut self) {
+        while let Ok(note) = self.late_results.try_recv() {
+            let _ = self.logger.log(format!("***WARNING: {}", note));
+        }
+    }
+
+    fn configure_logger(&mut self) {
+        let core = SETTINGS.lock().unwrap().core.clone();
+        self.logger.set_json(core.log_json);
+        self.logger.set_timezone(core.log_timezone);
+        self.logger
+            .set_rollover(Rollover {
+                              hour: core.log_rollover_hour,
+                              idle: chrono::Duration::minutes(core.log_idle_minutes as i64),
+                          });
+    }
+
+    /// Compresses and deletes old logs on another thread, unless that is still running
+    fn run_maintenance(&mut self) {
+        if self.retention.is_some() {
+            return;
+        }
+        let retention = {
+            let core = &SETTINGS.lock().unwrap().core;
+            Retention {
+                compress_after: core.log_compress_after_days,
+                delete_after: core.log_delete_after_days,
+            }
+        };
+        if retention.compress_after == 0 && retention.delete_after == 0 {
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let log_dir = self.log_dir.clone();
+        let today = self.logger.today();
+        thread::spawn(move || {
+                          let _ = tx.send(logger::apply_retention(log_dir, retention, today));
+                      });
+        self.retention = Some(rx);
+    }
+
+    fn log_retention_result(&mut self) {
+        let summary = match self.retention.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(summary)) => summary,
+            Some(Err(TryRecvError::Disconnected)) => {
+                let _ = self.logger.log("***WARNING: The log retention failed");
+                self.retention = None;
+                return;
+            }
+            Some(Err(TryRecvError::Empty)) | None => return,
+        };
+        self.retention = None;
+        if summary.compressed > 0 || summary.deleted > 0 {
+            let _ = self.logger
+                .log(format!("*** Compressed {} and deleted {} old log files ***",
+                             summary.compressed,
+                             summary.deleted));
+        }
+        for error in summary.errors {
+            let _ = self.logger
+                .log(format!("***WARNING: Log retention: {}", error));
+        }
+    }
+
+    /// Re-reads the settings file; the old settings are kept if it is invalid
+    fn reload_settings(&mut self) -> Result<(), ConfigError> {
+        let settings = SETTINGS.lock().unwrap().reload()?;
+        {
+            let old = SETTINGS.lock().unwrap();
+            if settings.core.token != old.core.token || settings.core.backend != old.core.backend {
+                let _ = self.logger
+                    .log("***WARNING: Changes to the token and the backend need a restart");
+            }
+        }
+
+        *SETTINGS.lock().unwrap() = settings;
+        self.apply_settings();
+        let _ = self.logger.log("*** Reloaded settings ***");
+        Ok(())
+    }
+
+    /// Updates what the core keeps from the settings after they change
+    fn apply_settings(&mut self) {
+        {
+            let settings = SETTINGS.lock().unwrap();
+            for (i, plugin) in self.plugins.iter_mut().enumerate() {
+                let budget = settings
+                    .plugin(plugin.name())
+                    .time_budget
+                    .unwrap_or(settings.core.plugin_time_budget);
+                plugin.set_budget(Duration::from_millis(budget));
+                self.filters[i] = ChannelFilter::from_settings(plugin.name(), &settings);
+            }
+            self.tick_interval = Duration::from_secs(settings.core.tick_interval);
+        }
+        self.configure_logger();
+
+        // the admins may have changed
+        self.update_admins();
+    }
+
+    /// Resolves the `admins` setting against the known users and updates their roles
+    fn update_admins(&mut self) {
+        let members: Vec<_> = self.members.values().cloned().collect();
+        let mut warnings = Vec::new();
+        self.admins = {
+            let settings = SETTINGS.lock().unwrap();
+            permissions::resolve_admins(&settings.core.admins, &members, &mut warnings)
+        };
+        for warning in warnings {
+            let _ = self.logger.log(format!("***WARNING: {}", warning));
+        }
+        for user in members {
+            self.update_user(&user);
+        }
+    }
+
+    fn reload_plugin(&mut self, i: usize) -> Result<(), String> {
+        let name = self.plugins[i].name();
+        let result = match self.plugins[i].reload() {
+            Ok(result) => result,
+            Err(e) => Err(format!("{}", e)),
+        };
+        match result {
+            Ok(()) => {
+                let _ = self.logger.log(format!("*** Reloaded plugin {} ***", name));
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.logger
+                    .log(format!("***WARNING: Plugin {} kept its old data: {}", name, e));
+                Err(format!("{}: {}", name, e))
+            }
+        }
+    }
+
+    /// Reloads the settings, then every plugin; returns a summary for the user
+    fn reload_all(&mut self) -> String {
+        let mut errors = Vec::new();
+        if let Err(e) = self.reload_settings() {
+            let _ = self.logger
+                .log(format!("***WARNING: Kept the old settings: {}", e));
+            errors.push(format!("{}", e));
+        }
+        for i in 0..self.plugins.len() {
+            if let Err(e) = self.reload_plugin(i) {
+                errors.push(e);
+            }
+        }
+        self.update_file_times();
+
+        if errors.is_empty() {
+            "Reloaded the settings and all plugins.".to_string()
+        } else {
+            format!("Reloaded, except for (old data kept): {}", errors.join("; "))
+        }
+    }
+
+    /// The watched files and their last modification times
+    fn current_file_times(&self) -> HashMap<PathBuf, Option<SystemTime>> {
+        let mut files = vec![SETTINGS.lock().unwrap().path().to_path_buf()];
+        for plugin in &self.plugins {
+            files.extend(plugin.data_files().iter().map(PathBuf::from));
+        }
+        files
+            .into_iter()
+            .map(|path| {
+                     let time = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                     (path, time)
+                 })
+            .collect()
+    }
+
+    fn update_file_times(&mut self) {
+        self.file_times = self.current_file_times();
+    }
+
+    /// Reloads whatever changed on disk, if `watch_files` is set
+    fn check_watched_files(&mut self) {
+        if !SETTINGS.lock().unwrap().core.watch_files {
+            return;
+        }
+        let current = self.current_file_times();
+        let (settings_path, saved_time) = {
+            let settings = SETTINGS.lock().unwrap();
+            (settings.path().to_path_buf(), settings.saved_time())
+        };
+        // the bot's own saves, from commands or plugins, aren't edits to reload
+        if saved_time.is_some() && current.get(&settings_path) == Some(&saved_time) {
+            self.file_times.insert(settings_path.clone(), saved_time);
+        }
+        let changed: Vec<_> = current
+            .iter()
+            .filter(|&(path, time)| self.file_times.get(path) != Some(time))
+            .map(|(path, _)| path.clone())
+            .collect();
+        self.file_times = current;
+        if changed.is_empty() {
+            return;
+        }
+
+        let mut settings_reloaded = false;
+        if changed.contains(&settings_path) {
+            match self.reload_settings() {
+                Ok(()) => settings_reloaded = true,
+                Err(e) => {
+                    let _ = self.logger
+                        .log(format!("***WARNING: Kept the old settings: {}", e));
+                }
+            }
+        }
+        for i in 0..self.plugins.len() {
+            let plugin_changed = self.plugins[i]
+                .data_files()
+                .iter()
+                .any(|file| changed.contains(&PathBuf::from(file)));
+            if plugin_changed || settings_reloaded {
+                let _ = self.reload_plugin(i);
+            }
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        for plugin in &mut self.plugins {
+            if let Err(e) = plugin.shutdown() {
+                let _ = self.logger
+                    .log(format!("***WARNING: Plugin {} {} (shutdown)", plugin.name(), e));
+            }
+        }
+        let _ = self.logger.log("*** Shutting down ***");
+    }
+
+    fn resolve_channel(&self, channel: &str) -> String {
+        let channel = channel.trim_left_matches('#');
+        self.channels
+            .iter()
+            .find(|&(_, name)| name == channel)
+            .map(|(id, _)| id.clone())
+            .unwrap_or_else(|| channel.to_string())
+    }
+
+    /// The name of a channel given by ID, or the ID if the channel is unknown
+    fn channel_name(&self, channel: &str) -> String {
+        self.channels
+            .get(channel)
+            .cloned()
+            .unwrap_or_else(|| channel.to_string())
+    }
+
+    /// The name of the channel of a message; direct message and private channels opened
+    /// since connecting are named by their kind and remembered
+    fn message_channel_name(&mut self, backend: &ChatBackend, channel: &str, user: &str) -> String {
+        if let Some(name) = self.channels.get(channel) {
+            return name.clone();
+        }
+        let name = match backend.channel_kind(channel) {
+            ChannelKind::Public => return channel.to_string(),
+            // the other user of a direct message channel is the one writing in it
+            ChannelKind::Direct => self.direct_name(user),
+            ChannelKind::Private => format!("@#{}", channel),
+        };
+        self.channels.insert(channel.to_string(), name.clone());
+        name
+    }
+
+    /// The name direct messages with a user are logged under, in both directions
+    fn direct_name(&self, user: &str) -> String {
+        let name = self.users.get(user).map_or(user, |name| name.as_str());
+        format!("@{}", bare_nick(name))
+    }
+
+    /// The ID of a user given by ID or by nick, with or without the role prefix
+    fn resolve_user(&self, user: &str) -> String {
+        let user = bare_nick(user);
+        self.users
+            .iter()
+            .find(|&(_, name)| bare_nick(name) == user)
+            .map(|(id, _)| id.clone())
+            .unwrap_or_else(|| user.to_string())
+    }
+
+    fn perform_action(&mut self,
+                      backend: &ChatBackend,
+                      owner: Option<usize>,
+                      origin: Option<&Origin>,
+                      action: BotAction) {
+        let no_origin = || BackendError("There is no message to respond to".to_string());
+        // the ID and name of the channel a message goes to; direct messages are logged as @user
+        let origin_channel = origin.map(|origin| {
+                                            (Some(origin.channel.clone()),
+                                             self.channel_name(&origin.channel))
+                                        });
+        let (result, channel, message) = match action {
+            BotAction::Log(message) => {
+                let record = match origin_channel {
+                    Some((id, name)) => Record::new(RecordKind::Note).channel(id, &name),
+                    None => Record::new(RecordKind::System),
+                };
+                let _ = self.logger.log_record(&record.text(message));
+                return;
+            }
+            BotAction::Schedule(handle, timing, job) => {
+                self.scheduler
+                    .schedule(handle, timing, job, owner, origin.cloned());
+                return;
+            }
+            BotAction::Cancel(handle) => {
+                self.scheduler.cancel(handle);
+                return;
+            }
+            BotAction::Send(message) => {
+                (origin
+                     .ok_or_else(no_origin)
+                     .and_then(|origin| backend.send_message(&origin.channel, &message)),
+                 origin_channel,
+                 message)
+            }
+            BotAction::SendTo(channel, message) => {
+                let channel = self.resolve_channel(&channel);
+                let name = self.channel_name(&channel);
+                (backend.send_message(&channel, &message), Some((Some(channel), name)), message)
+            }
+            BotAction::SendDirect(user, message) => {
+                let user = self.resolve_user(&user);
+                let name = self.direct_name(&user);
+                (backend.send_direct(&user, &message), Some((None, name)), message)
+            }
+            BotAction::ReplyInThread(message) => {
+                (origin
+                     .ok_or_else(no_origin)
+                     .and_then(|origin| {
+                                   let thread_ts = origin.thread_ts.as_ref().unwrap_or(&origin.ts);
+                                   backend.send_in_thread(&origin.channel, thread_ts, &message)
+                               }),
+                 origin_channel,
+                 message)
+            }
+            BotAction::React(emoji) => {
+                let result = origin
+                    .ok_or_else(no_origin)
+                    .and_then(|origin| backend.add_reaction(&origin.channel, &origin.ts, &emoji));
+                if let Err(e) = result {
+                    let _ = self.logger
+                        .log(format!("***ERROR: Couldn't add reaction: {}", e));
+                }
+                return;
+            }
+        };
+
+        if let Err(e) = result {
+            let _ = self.logger
+                .log(format!("***ERROR: Couldn't send message: {}", e));
+        } else {
+            let self_user = backend.self_user();
+            let mut record = Record::new(RecordKind::Message)
+                .user(self_user.as_ref().map(|u| u.id.as_str()),
+                      &self_user.as_ref().map(|u| u.name.clone()).unwrap_or_default())
+                .text(message);
+            if let Some((id, name)) = channel {
+                record = record.channel(id, &name);
+            }
+            let _ = self.logger.log_record(&record);
+        }
     }
 }
 
 impl EventHandler for BotCore {
-    fn on_event(&mut self, client: &RtmClient, event: Event) {
+    fn on_event(&mut self, backend: &ChatBackend, event: Event) {
+        self.log_late_results();
+        self.log_retention_result();
         match event {
-            Event::Message(ref msg) => {
-                match **msg {
-                    Message::Standard(MessageStandard {
-                                          ref user,
-                                          ref text,
-                                          ref channel,
-                                          ..
-                                      }) => {
-                        let user = user.as_ref().unwrap();
-                        let channel = channel.as_ref().unwrap();
-                        let text = text.as_ref().unwrap();
-                        self.handle_message(client, user, channel, text);
-                    }
-                    _ => (),
+            Event::Message {
+                ref user,
+                ref channel,
+                ref text,
+                ref ts,
+                ref thread_ts,
+            } => {
+                self.handle_message(backend,
+                                    user,
+                                    channel,
+                                    text,
+                                    ts,
+                                    thread_ts.as_ref().map(|s| s.as_str()));
+            }
+            Event::UserJoined(ref user) |
+            Event::UserChanged(ref user) => {
+                self.update_user(user);
+            }
+            Event::ChannelCreated(ref channel) |
+            Event::ChannelJoined(ref channel) => {
+                let name = channel_name(channel);
+                self.channels.insert(channel.id.clone(), name);
+            }
+            Event::ChannelRenamed(ref channel) => {
+                let name = channel_name(channel);
+                if let Some(old_name) = self.channels
+                       .insert(channel.id.clone(), name.clone()) {
+                    let _ = self.logger
+                        .log(format!("*** #{} is now known as #{} ***", old_name, name));
+                }
+            }
+            Event::MessageChanged {
+                ref channel,
+                ref ts,
+                ref user,
+                ref text,
+            } => {
+                if let (&Some(ref user), &Some(ref text)) = (user, text) {
+                    let user_name = self.users.get(user).unwrap_or(user).clone();
+                    let channel_name = self.channel_name(channel);
+                    let _ = self.logger
+                        .log_record(&Record::new(RecordKind::Edit)
+                                         .channel(Some(channel), &channel_name)
+                                         .user(Some(user), &user_name)
+                                         .ts(ts, None)
+                                         .text(text.as_str()));
                 }
             }
-            _ => (),
+            Event::Dropped(ref reason) => {
+                let _ = self.logger
+                    .log_with_mode(format!("*** Ignored a {} ***", reason), LogMode::Console);
+            }
+            Event::Heartbeat => {
+                self.check_watched_files();
+            }
+            Event::MessageDeleted {
+                ref channel,
+                ref ts,
+            } => {
+                // only in the JSON log, as it's unknown who deleted what
+                let channel_name = self.channel_name(channel);
+                let _ = self.logger
+                    .log_record(&Record::new(RecordKind::Delete)
+                                     .channel(Some(channel), &channel_name)
+                                     .ts(ts, None));
+            }
         }
+
+        if let Some(kind) = event.kind() {
+            self.dispatch_event(backend, kind, event);
+        }
+        self.tick();
     }
 
-    fn on_close(&mut self, _: &RtmClient) {
+    fn on_close(&mut self, _: &ChatBackend) {
+        for plugin in &self.plugins {
+            if let Err(e) = plugin.on_disconnect() {
+                let _ = self.logger
+                    .log(format!("***WARNING: Plugin {} {} (disconnect)", plugin.name(), e));
+            }
+        }
         let _ = self.logger.log("*** Disconnected ***");
     }
 
-    fn on_connect(&mut self, client: &RtmClient) {
-        let resp = client.start_response();
-        if let Some(ref users) = resp.users {
-            for user in users {
-                let prefix = if Some(true) == user.is_primary_owner {
-                    "&"
-                } else if Some(true) == user.is_owner {
-                    "~"
-                } else if Some(true) == user.is_admin {
-                    "@"
-                } else {
-                    ""
-                };
-                self.users
-                    .insert(user.id.as_ref().cloned().unwrap(),
-                            format!("{}{}", prefix, &user.name.as_ref().unwrap()));
+    fn on_connect(&mut self, backend: &ChatBackend) {
+        for user in backend.users() {
+            self.update_user(&user);
+        }
+        self.update_admins();
+
+        for channel in backend.channels() {
+            let name = channel_name(&channel);
+            self.channels.insert(channel.id, name);
+        }
+
+        let mut actions = Vec::new();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            match plugin.on_connect() {
+                Ok(result) => {
+                    let owned = result.into_actions().into_iter().map(|action| (Some(i), action));
+                    actions.extend(owned);
+                }
+                Err(e) => {
+                    let _ = self.logger
+                        .log(format!("***WARNING: Plugin {} {} (connect)", plugin.name(), e));
+                }
             }
         }
+        for (owner, action) in actions {
+            self.perform_action(backend, owner, None, action);
+        }
 
-        if let Some(ref channels) = resp.channels {
-            for channel in channels {
-                self.channels
-                    .insert(channel.id.as_ref().cloned().unwrap(),
-                            channel.name.as_ref().cloned().unwrap());
+        let _ = self.logger.log("*** Connected ***");
+    }
+}
+
+/// The name a channel is known and logged by; the names of direct message and private
+/// channels start with `@`, which keeps them out of the log search
+fn channel_name(channel: &Channel) -> String {
+    match channel.kind {
+        ChannelKind::Public => channel.name.clone(),
+        ChannelKind::Private => format!("@#{}", channel.name),
+        ChannelKind::Direct => format!("@{}", channel.name),
+    }
+}
+
+/// A nick without its role prefix (`&`, `~` or `@`)
+fn bare_nick(name: &str) -> &str {
+    name.trim_left_matches(|c| c == '&' || c == '~' || c == '@')
+}
+
+/// The text to log instead of a command which sets a secret setting, without the value
+fn redact_command(command: &Command, command_char: &str) -> Option<String> {
+    if command.name != "config" || command.args.len() < 3 ||
+       command.args[0].to_lowercase() != "set" {
+        return None;
+    }
+    let key = &command.args[1];
+    if SETTINGS.lock().unwrap().is_secret(&key.to_lowercase()) {
+        Some(format!("{}config set {} (hidden)", command_char, key))
+    } else {
+        None
+    }
+}
+
+/// Loads the settings, migrating settings.ini next to them if only the old file exists
+fn load_settings(path: &Path) -> Result<Settings, ConfigError> {
+    let legacy_path = path.with_file_name(settings::LEGACY_FILE);
+    if !path.exists() && legacy_path.exists() {
+        match Settings::migrate(legacy_path.as_path(), path) {
+            Ok((settings, skipped)) => {
+                println!("Migrated {} to {}", legacy_path.display(), path.display());
+                for key in skipped {
+                    println!("Skipped the unknown or invalid setting {}", key);
+                }
+                return Ok(settings);
+            }
+            Err(e) => {
+                println!("Couldn't migrate {}: {}", legacy_path.display(), e);
             }
         }
+    }
+    let (settings, backup) = Settings::load_or_backup(path)?;
+    if let Some(backup) = backup {
+        println!("Couldn't load {}, using the backup {}",
+                 path.display(),
+                 backup.display());
+    }
+    Ok(settings)
+}
 
-        let _ = self.logger.log("*** Connected to Slack ***");
+/// Creates all used plugins and checks the settings they declare
+fn load_plugins() -> Result<Vec<Box<Plugin>>, String> {
+    let patterns = Patterns::new(SettingsHandle::new("patterns"))?;
+    let eightball = Eightball::new(SettingsHandle::new("eightball"))?;
+    let randomchat = RandomChat::new(SettingsHandle::new("randomchat"))?;
+    let logsearch = LogSearch::new(SettingsHandle::new("logsearch"));
+    let plugins: Vec<Box<Plugin>> = vec![Box::new(patterns),
+                                         Box::new(eightball),
+                                         Box::new(randomchat),
+                                         Box::new(logsearch)];
+
+    let mut settings = SETTINGS.lock().unwrap();
+    for plugin in &plugins {
+        settings
+            .register(plugin.plugin_name(), plugin.settings())
+            .map_err(|e| e.to_string())?;
     }
+    Ok(plugins)
 }
 
-fn main() {
-    let mut handler = BotCore::new();
-    // clone to avoid holding the lock
-    let token = SETTINGS.lock().unwrap().token.clone();
+fn print_usage(program: &str, options: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", options.usage(&brief));
+    println!("\nEnvironment variables LUCIDBOT_TOKEN, LUCIDBOT_BACKEND, \
+              LUCIDBOT_COMMAND_CHAR and LUCIDBOT_ADMINS override the settings.");
+}
 
-    if let Err(e) = RtmClient::login_and_run::<BotCore>(&token, &mut handler) {
-        println!("{:?}", e);
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut options = Options::new();
+    options.optopt("c",
+                   "config",
+                   &format!("settings file (default: $LUCIDBOT_CONFIG or {})",
+                            settings::DEFAULT_PATH),
+                   "FILE");
+    options.optopt("d",
+                   "data-dir",
+                   "directory of the data files and logs \
+                    (default: $LUCIDBOT_DATA_DIR or the current directory)",
+                   "DIR");
+    options.optflag("h", "help", "print this help");
+    let matches = match options.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(&args[0], &options);
+            process::exit(1);
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&args[0], &options);
+        return;
     }
+
+    let config_path = matches
+        .opt_str("c")
+        .or_else(|| env::var("LUCIDBOT_CONFIG").ok())
+        .unwrap_or_else(|| settings::DEFAULT_PATH.to_string());
+    let data_dir = matches
+        .opt_str("d")
+        .or_else(|| env::var("LUCIDBOT_DATA_DIR").ok())
+        .unwrap_or_else(|| ".".to_string());
+
+    let settings = load_settings(Path::new(&config_path)).and_then(|mut settings| {
+        settings.set_data_dir(&data_dir);
+        settings.apply_overrides(Overrides::from_env())?;
+        Ok(settings)
+    });
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+    let backend: Arc<ChatBackend> = match settings.core.backend.as_str() {
+        "console" => Arc::new(ConsoleBackend::new("lucidbot".to_string())),
+        _ => Arc::new(SlackBackend::new(settings.core.token.clone())),
+    };
+    *SETTINGS.lock().unwrap() = settings;
+    let plugins = match load_plugins() {
+        Ok(plugins) => plugins,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // the core runs on its own thread, so that slow plugins don't block the connection
+    let (queue, events) = EventQueue::new();
+    let core_thread = {
+        let backend = backend.clone();
+        let queue = queue.clone();
+        thread::spawn(move || {
+                          let mut handler = BotCore::new(queue, plugins);
+                          events.forward_to(&mut handler, &*backend);
+                          handler.shutdown();
+                      })
+    };
+
+    // the plugins get to save their data when the bot is stopped, not only when the
+    // backend gives up
+    shutdown::install_handlers();
+    shutdown::run_backend(backend, queue.clone(), shutdown::requested);
+
+    queue.stop();
+    let _ = core_thread.join();
 }
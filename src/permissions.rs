@@ -0,0 +1,116 @@
+use backend::User;
+use std::collections::HashSet;
+use std::fmt;
+
+/// What a user is allowed to do; every role can do everything the lower ones can
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    User,
+    Admin,
+    Owner,
+    PrimaryOwner,
+}
+
+impl Role {
+    /// Derives the role from the user's Slack flags and the IDs of the listed admins
+    pub fn of(user: &User, admins: &HashSet<String>) -> Role {
+        let role = if user.is_primary_owner {
+            Role::PrimaryOwner
+        } else if user.is_owner {
+            Role::Owner
+        } else if user.is_admin {
+            Role::Admin
+        } else {
+            Role::User
+        };
+
+        if role < Role::Admin && admins.contains(&user.id) {
+            Role::Admin
+        } else {
+            role
+        }
+    }
+
+    /// The prefix marking the role in nicks, like in IRC
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            Role::PrimaryOwner => "&",
+            Role::Owner => "~",
+            Role::Admin => "@",
+            Role::User => "",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Role::User => "user",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+            Role::PrimaryOwner => "primary owner",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Resolves the entries of the `admins` setting to user IDs. Anyone can take a free name, so
+/// an entry naming a known user is resolved to that user's ID only here, with a warning added
+/// to `warnings`; other entries are taken as IDs.
+pub fn resolve_admins(admins: &[String],
+                      users: &[User],
+                      warnings: &mut Vec<String>)
+                      -> HashSet<String> {
+    let mut ids = HashSet::new();
+    for admin in admins.iter().map(|admin| admin.trim().trim_left_matches('@')) {
+        if users.iter().any(|user| user.id == admin) {
+            ids.insert(admin.to_string());
+            continue;
+        }
+        match users.iter().find(|user| user.name.eq_ignore_ascii_case(admin)) {
+            Some(user) => {
+                warnings.push(format!("The admin {} is given by name; list the ID {} instead",
+                                      admin,
+                                      user.id));
+                ids.insert(user.id.clone());
+            }
+            None => {
+                ids.insert(admin.to_string());
+            }
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, name: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: name.to_string(),
+            is_primary_owner: false,
+            is_owner: false,
+            is_admin: false,
+        }
+    }
+
+    #[test]
+    fn admins_are_matched_by_id() {
+        let users = vec![user("U1", "alice"), user("U2", "bob")];
+        let mut warnings = Vec::new();
+        let admins = resolve_admins(&["U1".to_string(), "@Bob".to_string()],
+                                    &users,
+                                    &mut warnings);
+        assert_eq!(Role::of(&users[0], &admins), Role::Admin);
+        assert_eq!(Role::of(&users[1], &admins), Role::Admin);
+        assert_eq!(warnings.len(), 1);
+
+        // a user who takes the name later isn't an admin
+        let impostor = user("U3", "bob");
+        assert_eq!(Role::of(&impostor, &admins), Role::User);
+        let renamed = user("U4", "U1");
+        assert_eq!(Role::of(&renamed, &admins), Role::User);
+    }
+}
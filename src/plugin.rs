@@ -1,8 +1,70 @@
-use BotEvent;
+use {BotEvent, ResumeEventHandling};
 use MessageData;
+use backend::{Event, EventKind};
+use command::{Command, CommandSpec};
+use scheduler::JobHandle;
+use settings::SettingSpec;
+use std::path::PathBuf;
+
+pub trait Plugin: Send {
+    /// A unique, lowercase name identifying the plugin in settings and commands
+    fn plugin_name(&self) -> &'static str;
 
-pub trait Plugin {
     fn plugin_priority(&self, user: &str, channel: &str, msg: &str) -> i16;
-    fn handle_command(&mut self, user: &str, channel: &str, params: Vec<String>) -> BotEvent;
+
+    /// The plugin's own settings, read and written through a `SettingsHandle`
+    fn settings(&self) -> Vec<SettingSpec> {
+        Vec::new()
+    }
+
+    /// The commands this plugin wants to receive in `handle_command`
+    fn commands(&self) -> Vec<CommandSpec> {
+        Vec::new()
+    }
+
+    fn handle_command(&mut self, _user: &str, _channel: &str, _command: &Command) -> BotEvent {
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
+
     fn handle_message(&mut self, data: MessageData) -> BotEvent;
+
+    /// Called after the bot connects to the chat
+    fn on_connect(&mut self) -> BotEvent {
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
+
+    /// Called after the connection to the chat is closed
+    fn on_disconnect(&mut self) {}
+
+    /// Called periodically, every `tick_interval` seconds
+    fn on_tick(&mut self) {}
+
+    /// Called once before the bot exits
+    fn on_shutdown(&mut self) {}
+
+    /// The kinds of events this plugin wants to receive in `handle_event`
+    fn subscriptions(&self) -> Vec<EventKind> {
+        Vec::new()
+    }
+
+    fn handle_event(&mut self, _event: &Event) -> BotEvent {
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
+
+    /// Files the plugin reads its data from, watched for changes if `watch_files` is set
+    fn data_files(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Re-reads the plugin's settings and data files.
+    ///
+    /// If the new data can't be loaded, the plugin must keep the old data and return the error.
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called when a `Job::Callback` scheduled by this plugin is due
+    fn on_scheduled(&mut self, _handle: JobHandle) -> BotEvent {
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
 }
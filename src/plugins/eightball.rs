@@ -1,24 +1,52 @@
 use {BotEvent, MessageData, ResumeEventHandling};
+use command::{ArgSpec, Command, CommandSpec};
 use plugin::Plugin;
 use rand::{sample, thread_rng};
+use settings::SettingsHandle;
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const RESPONSES_FILE: &'static str = "eightball.ini";
+
+/// Reads the responses, one per line; `%s` is replaced with the user's name
+fn load_responses(path: &Path) -> Result<Vec<String>, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let responses: Vec<_> = contents
+        .lines()
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.to_owned())
+        .collect();
+    if responses.is_empty() {
+        return Err(format!("{}: no responses", path.display()));
+    }
+    Ok(responses)
+}
 
 pub struct Eightball {
     responses: Vec<String>,
+    path: PathBuf,
 }
 
 impl Eightball {
-    pub fn new() -> io::Result<Eightball> {
-        let mut file = File::open("eightball.ini")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        Ok(Eightball { responses: contents.lines().map(|s| s.to_owned()).collect() })
+    pub fn new(settings: SettingsHandle) -> Result<Eightball, String> {
+        let path = settings.data_path(RESPONSES_FILE);
+        Ok(Eightball {
+               responses: load_responses(&path)?,
+               path: path,
+           })
     }
 }
 
 impl Plugin for Eightball {
+    fn plugin_name(&self) -> &'static str {
+        "eightball"
+    }
+
     fn plugin_priority(&self, _: &str, _: &str, _: &str) -> i16 {
         -1
     }
@@ -27,8 +55,27 @@ impl Plugin for Eightball {
         BotEvent::None(ResumeEventHandling::Resume)
     }
 
-    fn handle_command(&mut self, user: &str, _: &str, params: Vec<String>) -> BotEvent {
-        if params[0] == "eightball" {
+    fn data_files(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        self.responses = load_responses(&self.path)?;
+        Ok(())
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new("eightball", "Answers a yes/no question")
+                 .alias("8ball")
+                 .arg(ArgSpec::Rest("question"))]
+    }
+
+    fn handle_command(&mut self, user: &str, _: &str, command: &Command) -> BotEvent {
+        if command.name == "eightball" {
+            if command.raw.is_empty() {
+                return BotEvent::Send(format!("{}: ask me a question first!", user),
+                                      ResumeEventHandling::Stop);
+            }
             let mut rng = thread_rng();
             let sample = sample(&mut rng, &self.responses, 1);
             let result = sample[0].replace("%s", user);
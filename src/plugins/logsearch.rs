@@ -0,0 +1,241 @@
+use {BotAction, BotEvent, MessageData, ResumeEventHandling};
+use chrono::NaiveDate;
+use command::{ArgSpec, Command, CommandSpec};
+use log_index::{LogIndex, LogLine, Match};
+use plugin::Plugin;
+use regex::Regex;
+use settings::{SettingSpec, SettingsHandle};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const LOGS_DIR: &'static str = "logs";
+
+/// The index, once built, and the errors of reading the logs not reported yet
+struct Shared {
+    index: Option<LogIndex>,
+    errors: Vec<String>,
+    /// The bot's own name, whose lines aren't indexed, once a message told it
+    self_name: Option<String>,
+}
+
+pub struct LogSearch {
+    shared: Arc<Mutex<Shared>>,
+    settings: SettingsHandle,
+}
+
+/// Parses `YYYY-MM-DD`, `YYYY-MM-DD..YYYY-MM-DD`, `YYYY-MM-DD..` or `..YYYY-MM-DD`
+fn parse_dates(text: &str) -> Option<(Option<NaiveDate>, Option<NaiveDate>)> {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok();
+    let mut parts = text.splitn(2, "..");
+    let from = parts.next().unwrap_or("");
+    match parts.next() {
+        None => date(from).map(|day| (Some(day), Some(day))),
+        Some("") if from.is_empty() => None,
+        Some(to) => {
+            let from = if from.is_empty() { None } else { Some(date(from)?) };
+            let to = if to.is_empty() { None } else { Some(date(to)?) };
+            Some((from, to))
+        }
+    }
+}
+
+/// The first argument as typed; the parsed one has the backslashes of the regex removed
+fn raw_first_arg(raw: &str) -> &str {
+    if raw.starts_with('"') {
+        let rest = &raw[1..];
+        &rest[..rest.find('"').unwrap_or(rest.len())]
+    } else {
+        raw.split_whitespace().next().unwrap_or("")
+    }
+}
+
+/// Whether a line is a use of `!grep` or `!seen`, whatever the command character
+fn is_search_command(text: &str) -> bool {
+    let command = text.trim_left_matches(|c: char| !c.is_alphanumeric() && !c.is_whitespace());
+    command.len() < text.len() &&
+    ["grep", "seen"]
+        .iter()
+        .any(|name| {
+                 command.starts_with(name) &&
+                 command[name.len()..]
+                     .chars()
+                     .next()
+                     .map_or(true, char::is_whitespace)
+             })
+}
+
+fn format_match(found: &Match) -> String {
+    match found.channel {
+        Some(channel) => {
+            format!("[{}] #{} <{}> {}",
+                    found.line.time,
+                    channel,
+                    found.line.nick,
+                    found.line.text)
+        }
+        None => format!("[{}] <{}> {}", found.line.time, found.line.nick, found.line.text),
+    }
+}
+
+impl LogSearch {
+    /// Starts indexing the logs in the background
+    pub fn new(settings: SettingsHandle) -> LogSearch {
+        let shared = Arc::new(Mutex::new(Shared {
+                                             index: None,
+                                             errors: Vec::new(),
+                                             self_name: None,
+                                         }));
+        let mut index = LogIndex::new(settings.data_path(LOGS_DIR));
+        {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                              let errors = index.refresh();
+                              let mut shared = shared.lock().unwrap();
+                              if let Some(ref name) = shared.self_name {
+                                  index.ignore_user(name);
+                              }
+                              shared.index = Some(index);
+                              shared.errors.extend(errors);
+                          });
+        }
+        LogSearch {
+            shared: shared,
+            settings: settings,
+        }
+    }
+
+    fn grep(&self, index: &LogIndex, user: &str, command: &Command) -> Vec<BotAction> {
+        let regex = match Regex::new(&format!("(?i){}", raw_first_arg(&command.raw))) {
+            Ok(regex) => regex,
+            Err(e) => return vec![BotAction::Send(format!("Invalid regex: {}", e))],
+        };
+        let mut from_user = None;
+        let (mut from, mut to) = (None, None);
+        for arg in &command.args[1..] {
+            match parse_dates(arg) {
+                Some(dates) => {
+                    from = dates.0;
+                    to = dates.1;
+                }
+                None => from_user = Some(arg.as_str()),
+            }
+        }
+
+        let limit = self.settings.get_integer("max_results") as usize;
+        let filter = |line: &LogLine| regex.is_match(&line.text) && !is_search_command(&line.text);
+        let found = index.search(&filter, from_user, from, to, limit);
+        if found.is_empty() {
+            return vec![BotAction::Send("No matches.".to_string())];
+        }
+        let lines: Vec<String> = found.iter().map(format_match).collect();
+        if found.len() as i64 > self.settings.get_integer("direct_message_after") {
+            vec![BotAction::SendDirect(user.to_string(), lines.join("\n")),
+                 BotAction::Send(format!("Sent {} matches by direct message.", found.len()))]
+        } else {
+            vec![BotAction::Send(lines.join("\n"))]
+        }
+    }
+
+    fn seen(&self, index: &LogIndex, user: &str) -> String {
+        match index.seen(user) {
+            Some(seen) => {
+                let place = match seen.channel {
+                    Some(ref channel) => format!("in #{}", channel),
+                    None => "in the logs".to_string(),
+                };
+                format!("{} was last seen {} at {}, saying: {}",
+                        seen.line.nick,
+                        place,
+                        seen.line.time,
+                        seen.line.text)
+            }
+            None => format!("I haven't seen {}.", user),
+        }
+    }
+}
+
+impl Plugin for LogSearch {
+    fn plugin_name(&self) -> &'static str {
+        "logsearch"
+    }
+
+    fn plugin_priority(&self, _: &str, _: &str, _: &str) -> i16 {
+        0
+    }
+
+    fn settings(&self) -> Vec<SettingSpec> {
+        vec![SettingSpec::integer("max_results",
+                                  10,
+                                  1,
+                                  100,
+                                  "The most lines !grep returns"),
+             SettingSpec::integer("direct_message_after",
+                                  3,
+                                  0,
+                                  100,
+                                  "More !grep results than this are sent by direct message")]
+    }
+
+    fn handle_message(&mut self, data: MessageData) -> BotEvent {
+        let mut shared = self.shared.lock().unwrap();
+        let shared = &mut *shared;
+        if shared.self_name.as_ref().map(|name| name.as_str()) != Some(data.self_name) {
+            shared.self_name = Some(data.self_name.to_string());
+            if let Some(ref mut index) = shared.index {
+                index.ignore_user(data.self_name);
+            }
+        }
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
+
+    fn on_tick(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        let errors = match shared.index {
+            Some(ref mut index) => index.refresh(),
+            None => return,
+        };
+        shared.errors.extend(errors);
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new("grep",
+                              "Searches the logs, optionally for a user's lines or \
+                               dates like 2017-01-31 or 2017-01-01..2017-01-31")
+                 .arg(ArgSpec::Required("regex"))
+                 .arg(ArgSpec::Optional("user"))
+                 .arg(ArgSpec::Optional("dates")),
+             CommandSpec::new("seen", "Tells when and where a user last spoke")
+                 .arg(ArgSpec::Required("user"))]
+    }
+
+    fn handle_command(&mut self, user: &str, _: &str, command: &Command) -> BotEvent {
+        if command.name != "grep" && command.name != "seen" {
+            return BotEvent::None(ResumeEventHandling::Resume);
+        }
+
+        let mut shared = self.shared.lock().unwrap();
+        let errors = match shared.index {
+            Some(ref mut index) => index.refresh(),
+            None => {
+                return BotEvent::Send("The logs are still being indexed, try again in a \
+                                       moment."
+                                              .to_string(),
+                                      ResumeEventHandling::Stop)
+            }
+        };
+        shared.errors.extend(errors);
+        let mut actions: Vec<BotAction> = shared
+            .errors
+            .drain(..)
+            .map(|e| BotAction::Log(format!("***WARNING: Couldn't index {}", e)))
+            .collect();
+
+        let index = shared.index.as_ref().unwrap();
+        if command.name == "grep" {
+            actions.extend(self.grep(index, user, command));
+        } else {
+            actions.push(BotAction::Send(self.seen(index, &command.args[0])));
+        }
+        BotEvent::Actions(actions, ResumeEventHandling::Stop)
+    }
+}
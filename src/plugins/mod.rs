@@ -1,7 +1,9 @@
 mod eightball;
+mod logsearch;
 mod patterns;
 mod randomchat;
 
 pub use self::eightball::Eightball;
+pub use self::logsearch::LogSearch;
 pub use self::patterns::Patterns;
 pub use self::randomchat::RandomChat;
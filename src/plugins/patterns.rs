@@ -1,34 +1,53 @@
 use {BotEvent, MessageData, ResumeEventHandling};
 use plugin::Plugin;
 use regex::Regex;
+use settings::SettingsHandle;
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const PATTERNS_FILE: &'static str = "patterns.ini";
+
+/// Reads pairs of lines: a regex and the response to messages matching it
+fn load_patterns(path: &Path) -> Result<Vec<(Regex, String)>, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut patterns = Vec::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((i, pattern)) = lines.next() {
+        let (_, response) = lines
+            .next()
+            .ok_or_else(|| format!("{}:{}: a pattern without a response", path.display(), i + 1))?;
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        patterns.push((regex, response.to_string()));
+    }
+    Ok(patterns)
+}
 
 pub struct Patterns {
     patterns: Vec<(Regex, String)>,
+    path: PathBuf,
 }
 
 impl Patterns {
-    pub fn new() -> io::Result<Patterns> {
-        let mut file = File::open("patterns.ini")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let mut patterns = Vec::new();
-        let mut lines = contents.lines().peekable();
-        while lines.peek().is_some() {
-            let pattern = lines.next().unwrap();
-            let response = lines
-                .next()
-                .expect("A pattern without a response detected!");
-            patterns.push((Regex::new(pattern).unwrap(), response.to_string()));
-        }
-
-        Ok(Patterns { patterns: patterns })
+    pub fn new(settings: SettingsHandle) -> Result<Patterns, String> {
+        let path = settings.data_path(PATTERNS_FILE);
+        Ok(Patterns {
+               patterns: load_patterns(&path)?,
+               path: path,
+           })
     }
 }
 
 impl Plugin for Patterns {
+    fn plugin_name(&self) -> &'static str {
+        "patterns"
+    }
+
     fn plugin_priority(&self, _: &str, _: &str, _: &str) -> i16 {
         -1
     }
@@ -42,7 +61,12 @@ impl Plugin for Patterns {
         BotEvent::None(ResumeEventHandling::Resume)
     }
 
-    fn handle_command(&mut self, _: &str, _: &str, _: Vec<String>) -> BotEvent {
-        BotEvent::None(ResumeEventHandling::Resume)
+    fn data_files(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        self.patterns = load_patterns(&self.path)?;
+        Ok(())
     }
 }
@@ -1,101 +1,171 @@
-use {BotEvent, MessageData, ResumeEventHandling};
+use {BotAction, BotEvent, MessageData, ResumeEventHandling};
 use chrono::Duration;
+use command::{ArgSpec, Command, CommandSpec};
 use dictionary::Dictionary;
+use permissions::Role;
 use plugin::Plugin;
 use rand::{self, Rng};
-use settings::SETTINGS;
-use std::str::FromStr;
-use std::sync::{Arc, Mutex};
-use timer::{Guard, Timer};
+use scheduler::{Job, JobHandle, Timing};
+use settings::{SettingSpec, SettingsHandle};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const DICTIONARY_FILE: &'static str = "dictionary.dat";
 
 pub struct RandomChat {
-    dict: Arc<Mutex<Dictionary>>,
-    enabled: bool,
-    probability: u8,
-    autosave_timer: Option<Timer>,
-    autosave_guard: Option<Guard>,
+    dict: Dictionary,
+    dict_path: PathBuf,
+    /// The modification time of the dictionary when it was last loaded or saved here
+    dict_time: Option<SystemTime>,
+    settings: SettingsHandle,
+    autosave: JobHandle,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
 }
 
 impl RandomChat {
-    pub fn new() -> RandomChat {
-        let dict = Dictionary::load("dictionary.dat").unwrap();
-        let settings = SETTINGS.lock().unwrap();
-        RandomChat {
-            dict: Arc::new(Mutex::new(dict)),
-            enabled: settings.get_other("randomchat_enabled").unwrap() == "true",
-            probability: FromStr::from_str(settings.get_other("randomchat_probability").unwrap())
-                .unwrap(),
-            autosave_timer: None,
-            autosave_guard: None,
-        }
+    pub fn new(settings: SettingsHandle) -> Result<RandomChat, String> {
+        let dict_path = settings.data_path(DICTIONARY_FILE);
+        let dict = match Dictionary::load_or_backup(&dict_path) {
+            Ok((dict, None)) => dict,
+            Ok((dict, Some(backup))) => {
+                println!("Couldn't load {}, using the backup {}",
+                         dict_path.display(),
+                         backup.display());
+                dict
+            }
+            // the bot learns from scratch, but a broken dictionary mustn't get overwritten
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Dictionary::new(),
+            Err(e) => return Err(format!("Couldn't load {}: {}", dict_path.display(), e)),
+        };
+        Ok(RandomChat {
+               dict: dict,
+               dict_time: modified(&dict_path),
+               dict_path: dict_path,
+               settings: settings,
+               autosave: JobHandle::new(),
+           })
     }
 
-    fn init_timer(&mut self) {
-        if self.autosave_timer.is_none() {
-            self.autosave_timer = Some(Timer::new());
-            self.autosave_guard = {
-                let dict = self.dict.clone();
-                Some(self.autosave_timer
-                         .as_ref()
-                         .unwrap()
-                         .schedule_repeating(Duration::minutes(10), move || {
-                    let _ = dict.lock().unwrap().save("dictionary.dat");
-                }))
-            }
+    fn save(&mut self) -> Result<(), String> {
+        self.dict
+            .save(&self.dict_path)
+            .map_err(|e| format!("Couldn't save {}: {}", self.dict_path.display(), e))?;
+        self.dict_time = modified(&self.dict_path);
+        Ok(())
+    }
+
+    /// Saves the dictionary when the bot can't log the error
+    fn save_or_print(&mut self) {
+        if let Err(e) = self.save() {
+            eprintln!("{}", e);
         }
     }
 }
 
 impl Plugin for RandomChat {
+    fn plugin_name(&self) -> &'static str {
+        "randomchat"
+    }
+
     fn plugin_priority(&self, _: &str, _: &str, _: &str) -> i16 {
         10
     }
 
+    fn settings(&self) -> Vec<SettingSpec> {
+        vec![SettingSpec::bool("enabled", false, "Whether to learn from and answer messages"),
+             SettingSpec::integer("probability",
+                                  0,
+                                  0,
+                                  100,
+                                  "The chance of answering a message, in percent")]
+    }
+
     fn handle_message(&mut self, data: MessageData) -> BotEvent {
-        if !self.enabled {
+        if !self.settings.get_bool("enabled") {
             return BotEvent::None(ResumeEventHandling::Resume);
         }
-        self.init_timer();
         if data.self_name != data.user {
-            self.dict.lock().unwrap().learn_from_line(data.msg);
+            self.dict.learn_from_line(data.msg);
         }
-        if rand::thread_rng().gen_range(0, 100) < self.probability {
-            let response = self.dict.lock().unwrap().generate_sentence();
+        if rand::thread_rng().gen_range(0, 100) < self.settings.get_integer("probability") {
+            let response = self.dict.generate_sentence();
             BotEvent::Send(response, ResumeEventHandling::Resume)
         } else {
             BotEvent::None(ResumeEventHandling::Resume)
         }
     }
 
-    fn handle_command(&mut self, _: &str, _: &str, params: Vec<String>) -> BotEvent {
-        if params[0] == "gadaj" {
-            let response = self.dict.lock().unwrap().generate_sentence();
-            BotEvent::Send(response, ResumeEventHandling::Stop)
-        } else if params[0] == "random" {
-            if params.len() < 2 {
-                return BotEvent::Send(String::from("Not enough parameters"),
-                                      ResumeEventHandling::Stop);
+    fn on_connect(&mut self) -> BotEvent {
+        // scheduling again with the same handle just replaces the job after a reconnect
+        BotEvent::Actions(vec![BotAction::Schedule(self.autosave,
+                                                   Timing::Every(Duration::minutes(10)),
+                                                   Job::Callback)],
+                          ResumeEventHandling::Resume)
+    }
+
+    fn on_disconnect(&mut self) {
+        self.save_or_print();
+    }
+
+    fn on_shutdown(&mut self) {
+        self.save_or_print();
+    }
+
+    fn on_scheduled(&mut self, handle: JobHandle) -> BotEvent {
+        if handle == self.autosave {
+            if let Err(e) = self.save() {
+                return BotEvent::Actions(vec![BotAction::Log(format!("***WARNING: {}", e))],
+                                         ResumeEventHandling::Resume);
             }
-            if params[1] == "enable" {
-                self.enabled = true;
-                SETTINGS
-                    .lock()
-                    .unwrap()
-                    .set_other("randomchat_enabled".to_string(), "true".to_string());
-                BotEvent::Send(String::from("RandomChat enabled."),
-                               ResumeEventHandling::Stop)
-            } else if params[1] == "disable" {
-                self.enabled = false;
-                SETTINGS
-                    .lock()
-                    .unwrap()
-                    .set_other("randomchat_enabled".to_string(), "false".to_string());
-                BotEvent::Send(String::from("RandomChat disabled."),
-                               ResumeEventHandling::Stop)
+        }
+        BotEvent::None(ResumeEventHandling::Resume)
+    }
+
+    fn data_files(&self) -> Vec<PathBuf> {
+        vec![self.dict_path.clone()]
+    }
+
+    /// Loads the dictionary if it was replaced, by dict-gen for example; the bot's own saves
+    /// don't count, so the lines learned since aren't thrown away
+    fn reload(&mut self) -> Result<(), String> {
+        if modified(&self.dict_path) == self.dict_time {
+            return Ok(());
+        }
+        self.dict = Dictionary::load(&self.dict_path)
+            .map_err(|e| format!("Couldn't load {}: {}", self.dict_path.display(), e))?;
+        self.dict_time = modified(&self.dict_path);
+        Ok(())
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![CommandSpec::new("gadaj", "Generates a random sentence"),
+             CommandSpec::new("random", "Enables or disables random chatting")
+                 .arg(ArgSpec::Required("enable|disable"))
+                 .role(Role::Admin)]
+    }
+
+    fn handle_command(&mut self, _: &str, _: &str, command: &Command) -> BotEvent {
+        if command.name == "gadaj" {
+            let response = self.dict.generate_sentence();
+            BotEvent::Send(response, ResumeEventHandling::Stop)
+        } else if command.name == "random" {
+            let param = command.args[0].to_lowercase();
+            let response = if param == "enable" || param == "disable" {
+                let enabled = param == "enable";
+                match self.settings.set_bool("enabled", enabled) {
+                    Ok(()) if enabled => String::from("RandomChat enabled."),
+                    Ok(()) => String::from("RandomChat disabled."),
+                    Err(e) => e,
+                }
             } else {
-                BotEvent::Send(format!("Unknown parameter value: {}", params[1]).to_string(),
-                               ResumeEventHandling::Stop)
-            }
+                format!("Unknown parameter value: {}", command.args[0])
+            };
+            BotEvent::Send(response, ResumeEventHandling::Stop)
         } else {
             BotEvent::None(ResumeEventHandling::Resume)
         }
@@ -0,0 +1,67 @@
+use BotCore;
+use backend::{ChatBackend, Event, EventHandler};
+use scheduler::JobHandle;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+enum QueuedEvent {
+    Event(Event),
+    Connect,
+    Close,
+    JobDue(JobHandle),
+    Stop,
+}
+
+/// An event handler which only queues the events, to be handled on another thread
+#[derive(Clone)]
+pub struct EventQueue {
+    tx: Sender<QueuedEvent>,
+}
+
+pub struct QueuedEvents {
+    rx: Receiver<QueuedEvent>,
+}
+
+impl EventQueue {
+    pub fn new() -> (EventQueue, QueuedEvents) {
+        let (tx, rx) = mpsc::channel();
+        (EventQueue { tx: tx }, QueuedEvents { rx: rx })
+    }
+
+    pub fn job_due(&self, handle: JobHandle) {
+        let _ = self.tx.send(QueuedEvent::JobDue(handle));
+    }
+
+    /// Makes the receiving side stop after handling the events queued so far
+    pub fn stop(&self) {
+        let _ = self.tx.send(QueuedEvent::Stop);
+    }
+}
+
+impl EventHandler for EventQueue {
+    fn on_event(&mut self, _: &ChatBackend, event: Event) {
+        let _ = self.tx.send(QueuedEvent::Event(event));
+    }
+
+    fn on_connect(&mut self, _: &ChatBackend) {
+        let _ = self.tx.send(QueuedEvent::Connect);
+    }
+
+    fn on_close(&mut self, _: &ChatBackend) {
+        let _ = self.tx.send(QueuedEvent::Close);
+    }
+}
+
+impl QueuedEvents {
+    /// Passes the queued events to the core, in order, until the queue is stopped
+    pub fn forward_to(self, core: &mut BotCore, backend: &ChatBackend) {
+        for event in self.rx {
+            match event {
+                QueuedEvent::Event(event) => core.on_event(backend, event),
+                QueuedEvent::Connect => core.on_connect(backend),
+                QueuedEvent::Close => core.on_close(backend),
+                QueuedEvent::JobDue(handle) => core.on_job_due(backend, handle),
+                QueuedEvent::Stop => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use {BotAction, Origin};
+use chrono::Duration;
+use queue::EventQueue;
+use std::collections::HashMap;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use timer::{Guard, Timer};
+
+static NEXT_HANDLE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Identifies a scheduled job, so that it can be cancelled
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct JobHandle(usize);
+
+impl JobHandle {
+    /// Creates a new, unique handle
+    pub fn new() -> JobHandle {
+        JobHandle(NEXT_HANDLE.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+#[derive(Clone)]
+pub enum Timing {
+    /// Run once, after the given time
+    After(Duration),
+    /// Run repeatedly, every given time
+    Every(Duration),
+}
+
+#[derive(Clone)]
+pub enum Job {
+    /// Perform the actions as if they were returned at the time of scheduling
+    Actions(Vec<BotAction>),
+    /// Call `on_scheduled` of the plugin which scheduled the job
+    Callback,
+    /// Run the core's housekeeping, such as the log retention
+    Maintenance,
+}
+
+/// A job which is due to be run
+pub struct DueJob {
+    /// The index of the plugin that scheduled the job, if any
+    pub owner: Option<usize>,
+    /// The message that was being handled when the job was scheduled
+    pub origin: Option<Origin>,
+    pub job: Job,
+}
+
+struct ScheduledJob {
+    owner: Option<usize>,
+    origin: Option<Origin>,
+    job: Job,
+    repeating: bool,
+    _guard: Guard,
+}
+
+/// Runs jobs at requested times.
+///
+/// The timer only puts due jobs on the event queue, so they are run on the
+/// core thread, in order with other events. Jobs are kept independently of the
+/// connection, so they survive reconnects.
+pub struct Scheduler {
+    timer: Timer,
+    queue: EventQueue,
+    jobs: HashMap<JobHandle, ScheduledJob>,
+}
+
+impl Scheduler {
+    pub fn new(queue: EventQueue) -> Scheduler {
+        Scheduler {
+            timer: Timer::new(),
+            queue: queue,
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// Schedules a job; a job already scheduled with the same handle is replaced
+    pub fn schedule(&mut self,
+                    handle: JobHandle,
+                    timing: Timing,
+                    job: Job,
+                    owner: Option<usize>,
+                    origin: Option<Origin>) {
+        let queue = self.queue.clone();
+        let callback = move || queue.job_due(handle);
+        let (guard, repeating) = match timing {
+            Timing::After(delay) => (self.timer.schedule_with_delay(delay, callback), false),
+            Timing::Every(interval) => (self.timer.schedule_repeating(interval, callback), true),
+        };
+
+        self.jobs
+            .insert(handle,
+                    ScheduledJob {
+                        owner: owner,
+                        origin: origin,
+                        job: job,
+                        repeating: repeating,
+                        _guard: guard,
+                    });
+    }
+
+    /// Cancels the job; returns false if there was no such job
+    pub fn cancel(&mut self, handle: JobHandle) -> bool {
+        self.jobs.remove(&handle).is_some()
+    }
+
+    /// Returns the job to be run now. One-time jobs are removed from the schedule.
+    pub fn take_due(&mut self, handle: JobHandle) -> Option<DueJob> {
+        let repeating = match self.jobs.get(&handle) {
+            Some(job) => job.repeating,
+            None => return None,
+        };
+
+        if repeating {
+            self.jobs
+                .get(&handle)
+                .map(|job| {
+                         DueJob {
+                             owner: job.owner,
+                             origin: job.origin.clone(),
+                             job: job.job.clone(),
+                         }
+                     })
+        } else {
+            self.jobs
+                .remove(&handle)
+                .map(|job| {
+                         DueJob {
+                             owner: job.owner,
+                             origin: job.origin,
+                             job: job.job,
+                         }
+                     })
+        }
+    }
+}
@@ -1,74 +1,1507 @@
+use dictionary::persist;
+use logger::LogTimezone;
 use regex::Regex;
-use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
 use std::fs;
-use std::hash::Hash;
-use std::io::{Read, Write};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use toml::Value;
+use toml::value::Table;
 
-pub struct Settings {
-    path: PathBuf,
+pub const DEFAULT_PATH: &'static str = "settings.toml";
+/// The old `"key" : "value"` format, migrated on first start; looked for next to the settings
+pub const LEGACY_FILE: &'static str = "settings.ini";
+/// The number of previous versions kept as `settings.toml.1` and so on
+pub const BACKUPS: usize = 3;
+/// Settings named with one of these endings hold secrets, even if not declared secret
+const SECRET_SUFFIXES: &'static [&'static str] = &["token", "key", "secret", "password"];
+
+/// Settings of the bot itself, the `[core]` section
+#[derive(Clone)]
+pub struct CoreSettings {
     pub token: String,
     pub command_char: String,
-    other: HashMap<String, String>,
+    /// `slack` or `console`
+    pub backend: String,
+    /// User IDs given the admin role; a user name is resolved to the ID on connecting
+    pub admins: Vec<String>,
+    /// Seconds between plugin ticks
+    pub tick_interval: u64,
+    /// Milliseconds a plugin has to handle an event, unless set for the plugin
+    pub plugin_time_budget: u64,
+    /// Reload the settings and plugin data files when they change
+    pub watch_files: bool,
+    /// Also write the logs as JSON lines, next to the text logs
+    pub log_json: bool,
+    /// Days after which logs are gzipped, 0 for never
+    pub log_compress_after_days: u64,
+    /// Days after which logs are deleted, 0 for never
+    pub log_delete_after_days: u64,
+    /// The hour the logs move on to the next day's file
+    pub log_rollover_hour: u32,
+    /// Minutes without logging after which the next day's file starts before the rollover hour
+    pub log_idle_minutes: u64,
+    pub log_timezone: LogTimezone,
+}
+
+impl Default for CoreSettings {
+    fn default() -> CoreSettings {
+        CoreSettings {
+            token: String::new(),
+            command_char: "!".to_string(),
+            backend: "slack".to_string(),
+            admins: Vec::new(),
+            tick_interval: 60,
+            plugin_time_budget: 1000,
+            watch_files: false,
+            log_json: false,
+            log_compress_after_days: 0,
+            log_delete_after_days: 0,
+            log_rollover_hour: 6,
+            log_idle_minutes: 240,
+            log_timezone: LogTimezone::Local,
+        }
+    }
+}
+
+/// Core settings given in environment variables, which take precedence over the file.
+///
+/// They are never written back to the file.
+#[derive(Clone, Default)]
+pub struct Overrides {
+    /// `LUCIDBOT_TOKEN`
+    pub token: Option<String>,
+    /// `LUCIDBOT_BACKEND`
+    pub backend: Option<String>,
+    /// `LUCIDBOT_COMMAND_CHAR`
+    pub command_char: Option<String>,
+    /// `LUCIDBOT_ADMINS`, comma-separated
+    pub admins: Option<Vec<String>>,
+}
+
+impl Overrides {
+    pub fn from_env() -> Overrides {
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        Overrides {
+            token: var("LUCIDBOT_TOKEN"),
+            backend: var("LUCIDBOT_BACKEND"),
+            command_char: var("LUCIDBOT_COMMAND_CHAR"),
+            admins: var("LUCIDBOT_ADMINS").map(|admins| split_list(&admins)),
+        }
+    }
+
+    /// The names of the overridden core settings
+    pub fn keys(&self) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.token.is_some() {
+            keys.push("token");
+        }
+        if self.backend.is_some() {
+            keys.push("backend");
+        }
+        if self.command_char.is_some() {
+            keys.push("command_char");
+        }
+        if self.admins.is_some() {
+            keys.push("admins");
+        }
+        keys
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn check_backend(backend: &str) -> Result<(), String> {
+    if backend == "slack" || backend == "console" {
+        Ok(())
+    } else {
+        Err(format!("unknown backend {}, expected slack or console", backend))
+    }
+}
+
+/// Settings every plugin has, from its `[plugins.<name>]` section
+#[derive(Clone, Default)]
+pub struct PluginSettings {
+    pub time_budget: Option<u64>,
+    pub channels_allow: Vec<String>,
+    pub channels_deny: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SettingType {
+    Bool,
+    /// An integer within the given bounds, inclusive
+    Integer(i64, i64),
+    String,
+    /// A list of strings
+    List,
+}
+
+impl SettingType {
+    /// Checks that the value is of this type
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        match (*self, value) {
+            (SettingType::Bool, &Value::Boolean(_)) |
+            (SettingType::String, &Value::String(_)) => Ok(()),
+            (SettingType::Integer(min, max), &Value::Integer(i)) => {
+                if i >= min && i <= max {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not between {} and {}", i, min, max))
+                }
+            }
+            (SettingType::List, &Value::Array(ref items)) => {
+                if items.iter().all(|item| item.as_str().is_some()) {
+                    Ok(())
+                } else {
+                    Err("expected a list of strings".to_string())
+                }
+            }
+            (_, value) => Err(format!("expected {}, found {}", self, value.type_str())),
+        }
+    }
+
+    /// Parses a value given as text, as in chat commands; lists are comma-separated
+    pub fn parse(&self, text: &str) -> Result<Value, String> {
+        let text = text.trim();
+        let value = match *self {
+            SettingType::Bool => {
+                match text.to_lowercase().as_str() {
+                    "true" | "yes" | "on" => Value::Boolean(true),
+                    "false" | "no" | "off" => Value::Boolean(false),
+                    _ => return Err(format!("expected {}, found {}", self, text)),
+                }
+            }
+            SettingType::Integer(..) => {
+                Value::Integer(text.parse()
+                                   .map_err(|_| format!("expected {}, found {}", self, text))?)
+            }
+            SettingType::String => Value::String(text.to_string()),
+            SettingType::List => string_array(&split_list(text)),
+        };
+        self.check(&value)?;
+        Ok(value)
+    }
+}
+
+impl fmt::Display for SettingType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SettingType::Bool => write!(f, "true or false"),
+            SettingType::Integer(min, max) if max == i64::max_value() => {
+                write!(f, "an integer of at least {}", min)
+            }
+            SettingType::Integer(min, max) => write!(f, "an integer from {} to {}", min, max),
+            SettingType::String => write!(f, "a string"),
+            SettingType::List => write!(f, "a list of strings"),
+        }
+    }
+}
+
+/// Describes a setting specific to a plugin
+#[derive(Clone)]
+pub struct SettingSpec {
+    pub name: &'static str,
+    pub kind: SettingType,
+    pub default: Value,
+    pub help: &'static str,
+    /// The value is never shown, like the token
+    pub secret: bool,
+}
+
+impl SettingSpec {
+    pub fn bool(name: &'static str, default: bool, help: &'static str) -> SettingSpec {
+        SettingSpec {
+            name: name,
+            kind: SettingType::Bool,
+            default: Value::Boolean(default),
+            help: help,
+            secret: false,
+        }
+    }
+
+    pub fn integer(name: &'static str,
+                   default: i64,
+                   min: i64,
+                   max: i64,
+                   help: &'static str)
+                   -> SettingSpec {
+        SettingSpec {
+            name: name,
+            kind: SettingType::Integer(min, max),
+            default: Value::Integer(default),
+            help: help,
+            secret: false,
+        }
+    }
+
+    pub fn string(name: &'static str, default: &str, help: &'static str) -> SettingSpec {
+        SettingSpec {
+            name: name,
+            kind: SettingType::String,
+            default: Value::String(default.to_string()),
+            help: help,
+            secret: false,
+        }
+    }
+
+    pub fn list(name: &'static str, help: &'static str) -> SettingSpec {
+        SettingSpec {
+            name: name,
+            kind: SettingType::List,
+            default: Value::Array(Vec::new()),
+            help: help,
+            secret: false,
+        }
+    }
+
+    pub fn secret(mut self) -> SettingSpec {
+        self.secret = true;
+        self
+    }
+
+    /// Shows the value, unless it is secret
+    pub fn display(&self, value: &Value) -> String {
+        if !self.secret {
+            value.to_string()
+        } else if value.as_str().map_or(false, |s| s.is_empty()) {
+            "(not set)".to_string()
+        } else {
+            "(hidden)".to_string()
+        }
+    }
+}
+
+/// The settings in the `[core]` section
+fn core_specs() -> Vec<SettingSpec> {
+    let defaults = CoreSettings::default();
+    vec![SettingSpec::string("token", &defaults.token, "The Slack bot token").secret(),
+         SettingSpec::string("command_char",
+                             &defaults.command_char,
+                             "The prefix of commands"),
+         SettingSpec::string("backend",
+                             &defaults.backend,
+                             "The chat backend, slack or console"),
+         SettingSpec::list("admins", "User IDs given the admin role"),
+         SettingSpec::integer("tick_interval",
+                              defaults.tick_interval as i64,
+                              1,
+                              i64::max_value(),
+                              "Seconds between plugin ticks"),
+         SettingSpec::integer("plugin_time_budget",
+                              defaults.plugin_time_budget as i64,
+                              1,
+                              i64::max_value(),
+                              "Milliseconds a plugin has to handle an event"),
+         SettingSpec::bool("watch_files",
+                           defaults.watch_files,
+                           "Reload the settings and data files when they change"),
+         SettingSpec::bool("log_json",
+                           defaults.log_json,
+                           "Also write the logs as JSON lines"),
+         SettingSpec::integer("log_compress_after_days",
+                              defaults.log_compress_after_days as i64,
+                              0,
+                              i64::max_value(),
+                              "Days after which logs are gzipped, 0 for never"),
+         SettingSpec::integer("log_delete_after_days",
+                              defaults.log_delete_after_days as i64,
+                              0,
+                              i64::max_value(),
+                              "Days after which logs are deleted, 0 for never"),
+         SettingSpec::integer("log_rollover_hour",
+                              defaults.log_rollover_hour as i64,
+                              0,
+                              23,
+                              "The hour the logs move on to the next day's file"),
+         SettingSpec::integer("log_idle_minutes",
+                              defaults.log_idle_minutes as i64,
+                              1,
+                              i64::max_value(),
+                              "Minutes without logging after which a new day's file \
+                               starts before the rollover hour"),
+         SettingSpec::string("log_timezone",
+                             &defaults.log_timezone.to_string(),
+                             "The time zone of the logs: local, utc or an offset like +02:00")]
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// The line the error was found at, counted from 1
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Finds the line of a key within a table, for error messages; a key holding a table is
+/// found at its first header
+fn line_of(source: &str, section: &str, key: &str) -> Option<usize> {
+    let full_key = if section.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", section, key)
+    };
+    let mut current = String::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line.trim_matches(|c| c == '[' || c == ']')
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(".");
+            if current == section && key.is_empty() {
+                return Some(i + 1);
+            }
+            if !key.is_empty() &&
+               (current == full_key || current.starts_with(&format!("{}.", full_key))) {
+                return Some(i + 1);
+            }
+        } else if current == section && !key.is_empty() {
+            let name = line.split('=').next().unwrap_or("").trim().trim_matches('"');
+            if name == key && line.contains('=') {
+                return Some(i + 1);
+            }
+        }
+    }
+    None
+}
+
+/// Reads typed values out of a table, reporting errors with the line of the offending key
+struct Section<'a> {
+    source: &'a str,
+    path: &'a Path,
+    name: String,
+    table: &'a Table,
+}
+
+impl<'a> Section<'a> {
+    fn error(&self, key: &str, message: String) -> ConfigError {
+        let full_key = if key.is_empty() {
+            self.name.clone()
+        } else if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        };
+        ConfigError {
+            path: self.path.to_path_buf(),
+            line: line_of(self.source, &self.name, key),
+            message: format!("{}: {}", full_key, message),
+        }
+    }
+
+    fn check_keys(&self, known: &[&str]) -> Result<(), ConfigError> {
+        match self.table.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => {
+                Err(self.error(key,
+                               format!("unknown setting, expected one of: {}", known.join(", "))))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn string(&self, key: &str, default: &str) -> Result<String, ConfigError> {
+        match self.table.get(key) {
+            None => Ok(default.to_string()),
+            Some(&Value::String(ref s)) => Ok(s.clone()),
+            Some(value) => {
+                Err(self.error(key, format!("expected a string, found {}", value.type_str())))
+            }
+        }
+    }
+
+    fn boolean(&self, key: &str, default: bool) -> Result<bool, ConfigError> {
+        match self.table.get(key) {
+            None => Ok(default),
+            Some(&Value::Boolean(b)) => Ok(b),
+            Some(value) => {
+                Err(self.error(key, format!("expected true or false, found {}", value.type_str())))
+            }
+        }
+    }
+
+    /// An integer within the given bounds, inclusive
+    fn integer(&self, key: &str, min: i64, max: i64) -> Result<Option<i64>, ConfigError> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(&Value::Integer(i)) if i >= min && i <= max => Ok(Some(i)),
+            Some(&Value::Integer(i)) if max == i64::max_value() => {
+                Err(self.error(key, format!("{} is less than {}", i, min)))
+            }
+            Some(&Value::Integer(i)) => {
+                Err(self.error(key, format!("{} is not between {} and {}", i, min, max)))
+            }
+            Some(value) => {
+                Err(self.error(key, format!("expected an integer, found {}", value.type_str())))
+            }
+        }
+    }
+
+    fn string_list(&self, key: &str) -> Result<Vec<String>, ConfigError> {
+        let items = match self.table.get(key) {
+            None => return Ok(Vec::new()),
+            Some(&Value::Array(ref items)) => items,
+            Some(value) => {
+                return Err(self.error(key,
+                                      format!("expected a list of strings, found {}",
+                                              value.type_str())))
+            }
+        };
+        items
+            .iter()
+            .map(|item| match *item {
+                     Value::String(ref s) => Ok(s.clone()),
+                     ref value => {
+                         Err(self.error(key,
+                                        format!("expected a list of strings, found a {} in it",
+                                                value.type_str())))
+                     }
+                 })
+            .collect()
+    }
+
+    fn subsection(&self, key: &str) -> Result<Option<Section<'a>>, ConfigError> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(&Value::Table(ref table)) => {
+                Ok(Some(Section {
+                            source: self.source,
+                            path: self.path,
+                            name: if self.name.is_empty() {
+                                key.to_string()
+                            } else {
+                                format!("{}.{}", self.name, key)
+                            },
+                            table: table,
+                        }))
+            }
+            Some(value) => {
+                Err(self.error(key, format!("expected a section, found {}", value.type_str())))
+            }
+        }
+    }
+}
+
+fn parse_core(section: Option<Section>) -> Result<CoreSettings, ConfigError> {
+    let defaults = CoreSettings::default();
+    let section = match section {
+        Some(section) => section,
+        None => return Ok(defaults),
+    };
+    section
+        .check_keys(&["token",
+                      "command_char",
+                      "backend",
+                      "admins",
+                      "tick_interval",
+                      "plugin_time_budget",
+                      "watch_files",
+                      "log_json",
+                      "log_compress_after_days",
+                      "log_delete_after_days",
+                      "log_rollover_hour",
+                      "log_idle_minutes",
+                      "log_timezone"])?;
+
+    let command_char = section.string("command_char", &defaults.command_char)?;
+    if command_char.is_empty() {
+        return Err(section.error("command_char", "must not be empty".to_string()));
+    }
+    let backend = section.string("backend", &defaults.backend)?;
+    check_backend(&backend)
+        .map_err(|message| section.error("backend", message))?;
+    let log_timezone = section
+        .string("log_timezone", &defaults.log_timezone.to_string())?
+        .parse()
+        .map_err(|message| section.error("log_timezone", message))?;
+
+    Ok(CoreSettings {
+           token: section.string("token", &defaults.token)?,
+           command_char: command_char,
+           backend: backend,
+           admins: section.string_list("admins")?,
+           tick_interval: section
+               .integer("tick_interval", 1, i64::max_value())?
+               .map_or(defaults.tick_interval, |i| i as u64),
+           plugin_time_budget: section
+               .integer("plugin_time_budget", 1, i64::max_value())?
+               .map_or(defaults.plugin_time_budget, |i| i as u64),
+           watch_files: section.boolean("watch_files", defaults.watch_files)?,
+           log_json: section.boolean("log_json", defaults.log_json)?,
+           log_compress_after_days: section
+               .integer("log_compress_after_days", 0, i64::max_value())?
+               .map_or(defaults.log_compress_after_days, |i| i as u64),
+           log_delete_after_days: section
+               .integer("log_delete_after_days", 0, i64::max_value())?
+               .map_or(defaults.log_delete_after_days, |i| i as u64),
+           log_rollover_hour: section
+               .integer("log_rollover_hour", 0, 23)?
+               .map_or(defaults.log_rollover_hour, |i| i as u32),
+           log_idle_minutes: section
+               .integer("log_idle_minutes", 1, i64::max_value())?
+               .map_or(defaults.log_idle_minutes, |i| i as u64),
+           log_timezone: log_timezone,
+       })
+}
+
+/// Settings every plugin has; the other keys in a plugin's section are its own
+const PLUGIN_KEYS: &'static [&'static str] = &["time_budget", "channels_allow", "channels_deny"];
+
+fn parse_plugin(section: &Section) -> Result<PluginSettings, ConfigError> {
+    Ok(PluginSettings {
+           time_budget: section
+               .integer("time_budget", 1, i64::max_value())?
+               .map(|i| i as u64),
+           channels_allow: section.string_list("channels_allow")?,
+           channels_deny: section.string_list("channels_deny")?,
+       })
+}
+
+fn string_array(items: &[String]) -> Value {
+    Value::Array(items.iter().cloned().map(Value::String).collect())
+}
+
+pub struct Settings {
+    path: PathBuf,
+    /// The file as it was read, for finding the lines of invalid values
+    source: String,
+    /// Where the data files and logs are kept
+    data_dir: PathBuf,
+    pub core: CoreSettings,
+    /// The core settings as they are in the file, before applying the overrides
+    file_core: CoreSettings,
+    overrides: Overrides,
+    plugins: BTreeMap<String, PluginSettings>,
+    /// The plugins' own settings, by plugin
+    values: BTreeMap<String, Table>,
+    /// The settings the plugins have declared, by plugin
+    specs: BTreeMap<String, Vec<SettingSpec>>,
+    /// The modification time of the file after the last save, to tell it from outside edits
+    saved_time: Option<SystemTime>,
 }
 
 impl Settings {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Settings {
-        let path_buf = path.as_ref().to_path_buf();
-        let mut file = fs::File::open(path).ok().unwrap();
-        let mut settings = String::new();
-        file.read_to_string(&mut settings)
-            .expect("Couldn't read from file");
-
-        let mut values = HashMap::new();
+    /// Default settings, to be saved at the given path
+    pub fn new<P: AsRef<Path>>(path: P) -> Settings {
+        Settings {
+            path: path.as_ref().to_path_buf(),
+            source: String::new(),
+            data_dir: PathBuf::from("."),
+            core: CoreSettings::default(),
+            file_core: CoreSettings::default(),
+            overrides: Overrides::default(),
+            plugins: BTreeMap::new(),
+            values: BTreeMap::new(),
+            specs: BTreeMap::new(),
+            saved_time: None,
+        }
+    }
+
+    /// Loads the settings, falling back to defaults if the file doesn't exist
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Settings, ConfigError> {
+        let path = path.as_ref();
+        let mut source = String::new();
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut source)
+                    .map_err(|e| {
+                                 ConfigError {
+                                     path: path.to_path_buf(),
+                                     line: None,
+                                     message: format!("Couldn't read the file: {}", e),
+                                 }
+                             })?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Settings::new(path)),
+            Err(e) => {
+                return Err(ConfigError {
+                               path: path.to_path_buf(),
+                               line: None,
+                               message: format!("Couldn't open the file: {}", e),
+                           })
+            }
+        }
+        Settings::parse(path, &source)
+    }
+
+    /// Loads the settings, falling back to the newest backup that is valid.
+    ///
+    /// The second value is the backup used, if the file itself couldn't be loaded. If
+    /// nothing loads, the error is the one of the file itself.
+    pub fn load_or_backup<P: AsRef<Path>>(path: P)
+                                          -> Result<(Settings, Option<PathBuf>), ConfigError> {
+        let path = path.as_ref();
+        let loaded = persist::load_with_backups(path, BACKUPS, |file| {
+            // a missing file means the defaults, but a missing backup is no backup
+            if file != path && !file.exists() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no backup"));
+            }
+            Settings::from_file(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+        match loaded {
+            Ok((mut settings, backup)) => {
+                // saving goes to the file, not over the backup
+                settings.path = path.to_path_buf();
+                Ok((settings, backup))
+            }
+            Err(e) => {
+                let message = e.to_string();
+                Err(e.into_inner()
+                        .and_then(|inner| inner.downcast::<ConfigError>().ok())
+                        .map(|e| *e)
+                        .unwrap_or_else(|| {
+                                            ConfigError {
+                                                path: path.to_path_buf(),
+                                                line: None,
+                                                message: message,
+                                            }
+                                        }))
+            }
+        }
+    }
+
+    fn parse(path: &Path, source: &str) -> Result<Settings, ConfigError> {
+        let root = match Value::from_str(source) {
+            Ok(Value::Table(table)) => table,
+            Ok(value) => {
+                return Err(ConfigError {
+                               path: path.to_path_buf(),
+                               line: None,
+                               message: format!("expected a table of settings, found {}",
+                                                value.type_str()),
+                           })
+            }
+            Err(e) => {
+                let message = e.to_string();
+                return Err(ConfigError {
+                               path: path.to_path_buf(),
+                               line: e.line_col().map(|(line, _)| line + 1),
+                               message: message
+                                   .split(" at line ")
+                                   .next()
+                                   .unwrap_or(&message)
+                                   .to_string(),
+                           });
+            }
+        };
+        let root = Section {
+            source: source,
+            path: path,
+            name: String::new(),
+            table: &root,
+        };
+        root.check_keys(&["core", "plugins"])?;
+
+        let mut settings = Settings::new(path);
+        settings.core = parse_core(root.subsection("core")?)?;
+        settings.file_core = settings.core.clone();
+        if let Some(plugins) = root.subsection("plugins")? {
+            for name in plugins.table.keys() {
+                let section = plugins.subsection(name)?.unwrap();
+                settings.plugins.insert(name.clone(), parse_plugin(&section)?);
+                // the plugin's own settings are checked once it declares them
+                let values = section
+                    .table
+                    .iter()
+                    .filter(|&(key, _)| !PLUGIN_KEYS.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                settings.values.insert(name.clone(), values);
+            }
+        }
+        settings.source = source.to_string();
+        Ok(settings)
+    }
+
+    /// Converts a settings.ini file to the current format.
+    ///
+    /// Keys that don't map to any setting are returned, so that they can be reported.
+    pub fn migrate<P: AsRef<Path>>(legacy_path: P,
+                                   path: P)
+                                   -> io::Result<(Settings, Vec<String>)> {
+        let mut source = String::new();
+        fs::File::open(legacy_path)?.read_to_string(&mut source)?;
+
         let re = Regex::new(r#""([^"]+)"\s*:\s*"([^"]+)""#).unwrap();
+        let mut settings = Settings::new(path);
+        let mut skipped = Vec::new();
 
-        for line in settings.lines() {
-            if let Some(caps) = re.captures(line) {
-                let key = caps.at(1).unwrap();
-                let value = caps.at(2).unwrap();
-                values.insert(key.to_string(), value.to_string());
+        for caps in source.lines().filter_map(|line| re.captures(line)) {
+            let key = caps.at(1).unwrap();
+            let value = caps.at(2).unwrap();
+            let migrated = match key {
+                "token" => {
+                    settings.core.token = value.to_string();
+                    true
+                }
+                "command_char" => {
+                    settings.core.command_char = value.to_string();
+                    true
+                }
+                "backend" => {
+                    settings.core.backend = value.to_string();
+                    true
+                }
+                "admins" => {
+                    settings.core.admins = split_list(value);
+                    true
+                }
+                "tick_interval" => {
+                    value
+                        .parse()
+                        .map(|v| settings.core.tick_interval = v)
+                        .is_ok()
+                }
+                "plugin_time_budget" => {
+                    value
+                        .parse()
+                        .map(|v| settings.core.plugin_time_budget = v)
+                        .is_ok()
+                }
+                "watch_files" => {
+                    settings.core.watch_files = value == "true";
+                    true
+                }
+                "randomchat_enabled" => {
+                    settings.plugin_values_mut("randomchat")
+                        .insert("enabled".to_string(), Value::Boolean(value == "true"));
+                    true
+                }
+                "randomchat_probability" => {
+                    value
+                        .parse()
+                        .map(|p| {
+                                 settings.plugin_values_mut("randomchat")
+                                     .insert("probability".to_string(), Value::Integer(p))
+                             })
+                        .is_ok()
+                }
+                key if key.ends_with("_time_budget") => {
+                    let plugin = settings.plugin_mut(&key[..key.len() - "_time_budget".len()]);
+                    value.parse().map(|v| plugin.time_budget = Some(v)).is_ok()
+                }
+                key if key.ends_with("_channels_allow") => {
+                    let plugin = settings.plugin_mut(&key[..key.len() - "_channels_allow".len()]);
+                    plugin.channels_allow = split_list(value);
+                    true
+                }
+                key if key.ends_with("_channels_deny") => {
+                    let plugin = settings.plugin_mut(&key[..key.len() - "_channels_deny".len()]);
+                    plugin.channels_deny = split_list(value);
+                    true
+                }
+                _ => false,
+            };
+            if !migrated {
+                skipped.push(key.to_string());
             }
         }
 
-        Settings {
-            path: path_buf,
-            token: if let Some(token) = values.get("token") {
-                token.to_string()
-            } else {
-                String::new()
-            },
-            command_char: if let Some(cmd) = values.get("command_char") {
-                cmd.to_string()
-            } else {
-                "!".to_string()
-            },
-            other: values,
+        settings.file_core = settings.core.clone();
+        settings.save()?;
+        Ok((settings, skipped))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_data_dir<P: AsRef<Path>>(&mut self, data_dir: P) {
+        self.data_dir = data_dir.as_ref().to_path_buf();
+    }
+
+    /// The path of a data file or directory
+    pub fn data_path<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        self.data_dir.join(file)
+    }
+
+    /// Applies the overrides on top of the settings from the file
+    pub fn apply_overrides(&mut self, overrides: Overrides) -> Result<(), ConfigError> {
+        let error = |message: String| {
+            ConfigError {
+                path: self.path.clone(),
+                line: None,
+                message: message,
+            }
+        };
+        if let Some(ref backend) = overrides.backend {
+            check_backend(backend)
+                .map_err(|message| error(format!("LUCIDBOT_BACKEND: {}", message)))?;
+        }
+        if overrides.command_char.as_ref().map_or(false, |c| c.trim().is_empty()) {
+            return Err(error("LUCIDBOT_COMMAND_CHAR: must not be empty".to_string()));
+        }
+
+        let mut core = self.file_core.clone();
+        if let Some(ref token) = overrides.token {
+            core.token = token.clone();
+        }
+        if let Some(ref backend) = overrides.backend {
+            core.backend = backend.clone();
+        }
+        if let Some(ref command_char) = overrides.command_char {
+            core.command_char = command_char.clone();
+        }
+        if let Some(ref admins) = overrides.admins {
+            core.admins = admins.clone();
+        }
+        self.core = core;
+        self.overrides = overrides;
+        Ok(())
+    }
+
+    /// The names of core settings set by environment variables
+    pub fn overridden(&self) -> Vec<&'static str> {
+        self.overrides.keys()
+    }
+
+    fn core_value(&self, key: &str) -> Option<Value> {
+        let core = &self.core;
+        let value = match key {
+            "token" => Value::String(core.token.clone()),
+            "command_char" => Value::String(core.command_char.clone()),
+            "backend" => Value::String(core.backend.clone()),
+            "admins" => string_array(&core.admins),
+            "tick_interval" => Value::Integer(core.tick_interval as i64),
+            "plugin_time_budget" => Value::Integer(core.plugin_time_budget as i64),
+            "watch_files" => Value::Boolean(core.watch_files),
+            "log_json" => Value::Boolean(core.log_json),
+            "log_compress_after_days" => Value::Integer(core.log_compress_after_days as i64),
+            "log_delete_after_days" => Value::Integer(core.log_delete_after_days as i64),
+            "log_rollover_hour" => Value::Integer(core.log_rollover_hour as i64),
+            "log_idle_minutes" => Value::Integer(core.log_idle_minutes as i64),
+            "log_timezone" => Value::String(core.log_timezone.to_string()),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Sets a core setting; the value must already be checked against its type
+    fn set_core_value(&mut self, key: &str, value: Value) -> Result<(), String> {
+        if self.overridden().contains(&key) {
+            return Err(format!("core.{} is set by an environment variable", key));
+        }
+        let core = &mut self.core;
+        match (key, value) {
+            ("token", Value::String(s)) => core.token = s,
+            ("command_char", Value::String(s)) => {
+                if s.is_empty() {
+                    return Err("core.command_char must not be empty".to_string());
+                }
+                core.command_char = s
+            }
+            ("backend", Value::String(s)) => {
+                check_backend(&s)?;
+                core.backend = s
+            }
+            ("admins", Value::Array(items)) => {
+                core.admins = items
+                    .iter()
+                    .filter_map(|item| item.as_str())
+                    .map(|s| s.to_string())
+                    .collect()
+            }
+            ("tick_interval", Value::Integer(i)) => core.tick_interval = i as u64,
+            ("plugin_time_budget", Value::Integer(i)) => core.plugin_time_budget = i as u64,
+            ("watch_files", Value::Boolean(b)) => core.watch_files = b,
+            ("log_json", Value::Boolean(b)) => core.log_json = b,
+            ("log_compress_after_days", Value::Integer(i)) => {
+                core.log_compress_after_days = i as u64
+            }
+            ("log_delete_after_days", Value::Integer(i)) => core.log_delete_after_days = i as u64,
+            ("log_rollover_hour", Value::Integer(i)) => core.log_rollover_hour = i as u32,
+            ("log_idle_minutes", Value::Integer(i)) => core.log_idle_minutes = i as u64,
+            ("log_timezone", Value::String(s)) => {
+                core.log_timezone = s.parse()
+                    .map_err(|message| format!("core.log_timezone: {}", message))?
+            }
+            _ => return Err(format!("core has no setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Finds the spec and the current value of a setting given as `core.<name>` or
+    /// `<plugin>.<name>`
+    fn lookup(&self, key: &str) -> Result<(SettingSpec, Value), String> {
+        let mut parts = key.splitn(2, '.');
+        let section = parts.next().unwrap_or("");
+        let name = parts.next().unwrap_or("");
+        let spec = if section == "core" {
+            core_specs().into_iter().find(|spec| spec.name == name)
+        } else {
+            self.spec(section, name).cloned()
+        };
+        let spec = spec.ok_or_else(|| format!("Unknown setting: {}", key))?;
+        let value = if section == "core" {
+            self.core_value(name)
+        } else {
+            self.plugin_value(section, name)
+        };
+        Ok((spec.clone(), value.unwrap_or(spec.default)))
+    }
+
+    /// Whether the value of a setting is kept out of the logs: true for secret settings,
+    /// settings named like one, and unknown settings, which may be misspelled secret ones
+    pub fn is_secret(&self, key: &str) -> bool {
+        SECRET_SUFFIXES.iter().any(|suffix| key.ends_with(suffix)) ||
+        self.lookup(key).map_or(true, |(spec, _)| spec.secret)
+    }
+
+    /// All settings with their values, secret ones hidden
+    pub fn list(&self) -> Vec<String> {
+        let mut keys: Vec<_> = core_specs()
+            .iter()
+            .map(|spec| format!("core.{}", spec.name))
+            .collect();
+        for (plugin, specs) in &self.specs {
+            keys.extend(specs.iter().map(|spec| format!("{}.{}", plugin, spec.name)));
         }
+        keys.iter()
+            .filter_map(|key| {
+                            self.lookup(key)
+                                .ok()
+                                .map(|(spec, value)| format!("{} = {}", key, spec.display(&value)))
+                        })
+            .collect()
+    }
+
+    /// Describes a setting and shows its value, unless it is secret
+    pub fn describe(&self, key: &str) -> Result<String, String> {
+        let (spec, value) = self.lookup(key)?;
+        Ok(format!("{} = {} ({}, default {}) - {}",
+                   key,
+                   spec.display(&value),
+                   spec.kind,
+                   spec.display(&spec.default),
+                   spec.help))
     }
 
-    pub fn save(&self) {
-        let mut file = fs::File::create(&self.path).unwrap();
-        for (key, value) in &self.other {
-            let line = format!("\"{}\" : \"{}\"\n", key, value);
-            let _ = file.write(line.as_bytes());
+    /// Sets a setting from text and saves the settings; returns the old and the new value
+    /// as they can be shown
+    pub fn set(&mut self, key: &str, text: &str) -> Result<(String, String), String> {
+        let (spec, old) = self.lookup(key)?;
+        let value = spec.kind
+            .parse(text)
+            .map_err(|e| format!("{}: {}", key, e))?;
+        let new = spec.display(&value);
+
+        let mut parts = key.splitn(2, '.');
+        let section = parts.next().unwrap_or("");
+        let name = parts.next().unwrap_or("");
+        if section == "core" {
+            self.set_core_value(name, value)?;
+            self.save()
+                .map_err(|e| format!("Couldn't save the settings: {}", e))?;
+        } else {
+            self.set_plugin_value(section, name, value)?;
         }
+        Ok((spec.display(&old), new))
     }
 
-    pub fn get_other<Q: ?Sized>(&self, key: &Q) -> Option<&String>
-        where String: Borrow<Q>,
-              Q: Hash + Eq
-    {
-        self.other.get(key)
+    /// Reads the settings again from the same file, checking them against the declared ones
+    pub fn reload(&self) -> Result<Settings, ConfigError> {
+        let mut settings = Settings::from_file(&self.path)?;
+        settings.data_dir = self.data_dir.clone();
+        settings.apply_overrides(self.overrides.clone())?;
+        for (plugin, specs) in &self.specs {
+            settings.register(plugin, specs.clone())?;
+        }
+        Ok(settings)
     }
 
-    pub fn set_other(&mut self, key: String, value: String) {
-        self.other.insert(key, value);
-        self.save();
+    /// Records the settings a plugin declares and checks the values set for it
+    pub fn register(&mut self, plugin: &str, specs: Vec<SettingSpec>) -> Result<(), ConfigError> {
+        let section = format!("plugins.{}", plugin);
+        if let Some(values) = self.values.get(plugin) {
+            for (key, value) in values {
+                let result = match specs.iter().find(|spec| spec.name == key) {
+                    Some(spec) => spec.kind.check(value),
+                    None => {
+                        let known: Vec<_> = PLUGIN_KEYS
+                            .iter()
+                            .cloned()
+                            .chain(specs.iter().map(|spec| spec.name))
+                            .collect();
+                        Err(format!("unknown setting, expected one of: {}", known.join(", ")))
+                    }
+                };
+                if let Err(message) = result {
+                    return Err(ConfigError {
+                                   path: self.path.clone(),
+                                   line: line_of(&self.source, &section, key),
+                                   message: format!("{}.{}: {}", section, key, message),
+                               });
+                }
+            }
+        }
+        self.specs.insert(plugin.to_string(), specs);
+        Ok(())
+    }
+
+    fn plugin_values_mut(&mut self, plugin: &str) -> &mut Table {
+        self.values
+            .entry(plugin.to_string())
+            .or_insert_with(Table::new)
+    }
+
+    /// The value of a plugin's own setting, or its default if it isn't set
+    pub fn plugin_value(&self, plugin: &str, key: &str) -> Option<Value> {
+        self.values
+            .get(plugin)
+            .and_then(|values| values.get(key))
+            .cloned()
+            .or_else(|| self.spec(plugin, key).map(|spec| spec.default.clone()))
+    }
+
+    /// Checks the value against the declared setting, then sets it and saves the settings
+    pub fn set_plugin_value(&mut self,
+                            plugin: &str,
+                            key: &str,
+                            value: Value)
+                            -> Result<(), String> {
+        self.spec(plugin, key)
+            .ok_or_else(|| format!("{} has no setting {}", plugin, key))?
+            .kind
+            .check(&value)?;
+        self.plugin_values_mut(plugin).insert(key.to_string(), value);
+        self.save()
+            .map_err(|e| format!("Couldn't save the settings: {}", e))
+    }
+
+    fn spec(&self, plugin: &str, key: &str) -> Option<&SettingSpec> {
+        self.specs
+            .get(plugin)
+            .and_then(|specs| specs.iter().find(|spec| spec.name == key))
+    }
+
+    /// Plugins which have declared settings of their own
+    pub fn plugins_with_settings(&self) -> Vec<&str> {
+        self.specs
+            .iter()
+            .filter(|&(_, specs)| !specs.is_empty())
+            .map(|(plugin, _)| plugin.as_str())
+            .collect()
+    }
+
+    /// One line per setting of the plugin: its name, value, type and help
+    pub fn describe_plugin(&self, plugin: &str) -> Vec<String> {
+        self.specs
+            .get(plugin)
+            .map(|specs| {
+                specs
+                    .iter()
+                    .map(|spec| {
+                             let value = self.plugin_value(plugin, spec.name)
+                                 .unwrap_or_else(|| spec.default.clone());
+                             format!("{}.{} = {} ({}, default {}) - {}",
+                                     plugin,
+                                     spec.name,
+                                     spec.display(&value),
+                                     spec.kind,
+                                     spec.display(&spec.default),
+                                     spec.help)
+                         })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The common settings of the plugin, which are all defaults if it has no section
+    pub fn plugin(&self, name: &str) -> PluginSettings {
+        self.plugins.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn plugin_mut(&mut self, name: &str) -> &mut PluginSettings {
+        self.plugins
+            .entry(name.to_string())
+            .or_insert_with(PluginSettings::default)
+    }
+
+    fn to_toml(&self) -> Value {
+        // overridden settings keep their values from the file
+        let overridden = self.overridden();
+        let saved = |key| if overridden.contains(&key) {
+            &self.file_core
+        } else {
+            &self.core
+        };
+
+        let mut core = Table::new();
+        core.insert("token".to_string(),
+                    Value::String(saved("token").token.clone()));
+        core.insert("command_char".to_string(),
+                    Value::String(saved("command_char").command_char.clone()));
+        core.insert("backend".to_string(),
+                    Value::String(saved("backend").backend.clone()));
+        core.insert("admins".to_string(), string_array(&saved("admins").admins));
+        core.insert("tick_interval".to_string(),
+                    Value::Integer(self.core.tick_interval as i64));
+        core.insert("plugin_time_budget".to_string(),
+                    Value::Integer(self.core.plugin_time_budget as i64));
+        core.insert("watch_files".to_string(),
+                    Value::Boolean(self.core.watch_files));
+        core.insert("log_json".to_string(), Value::Boolean(self.core.log_json));
+        core.insert("log_compress_after_days".to_string(),
+                    Value::Integer(self.core.log_compress_after_days as i64));
+        core.insert("log_delete_after_days".to_string(),
+                    Value::Integer(self.core.log_delete_after_days as i64));
+        core.insert("log_rollover_hour".to_string(),
+                    Value::Integer(self.core.log_rollover_hour as i64));
+        core.insert("log_idle_minutes".to_string(),
+                    Value::Integer(self.core.log_idle_minutes as i64));
+        core.insert("log_timezone".to_string(),
+                    Value::String(self.core.log_timezone.to_string()));
+
+        let mut plugins = Table::new();
+        for (name, values) in &self.values {
+            plugins.insert(name.clone(), Value::Table(values.clone()));
+        }
+        for (name, plugin) in &self.plugins {
+            let section = plugins
+                .entry(name.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if let Value::Table(ref mut section) = *section {
+                if let Some(budget) = plugin.time_budget {
+                    section.insert("time_budget".to_string(), Value::Integer(budget as i64));
+                }
+                section.insert("channels_allow".to_string(),
+                               string_array(&plugin.channels_allow));
+                section.insert("channels_deny".to_string(),
+                               string_array(&plugin.channels_deny));
+            }
+        }
+
+        let mut root = Table::new();
+        root.insert("core".to_string(), Value::Table(core));
+        root.insert("plugins".to_string(), Value::Table(plugins));
+        Value::Table(root)
+    }
+
+    /// Writes the settings back to their file atomically, keeping `BACKUPS` previous
+    /// versions; comments in it are not preserved
+    pub fn save(&mut self) -> io::Result<()> {
+        persist::write_atomic(&self.path,
+                              self.to_toml().to_string().as_bytes(),
+                              BACKUPS)?;
+        self.saved_time = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+
+    /// The modification time of the file after the bot last saved it
+    pub fn saved_time(&self) -> Option<SystemTime> {
+        self.saved_time
+    }
+}
+
+/// A plugin's view of the settings, limited to its own `[plugins.<name>]` section.
+///
+/// Getters fall back to the declared defaults. Settings that weren't declared with
+/// the requested type read as false or 0.
+#[derive(Clone)]
+pub struct SettingsHandle {
+    plugin: &'static str,
+}
+
+impl SettingsHandle {
+    pub fn new(plugin: &'static str) -> SettingsHandle {
+        SettingsHandle { plugin: plugin }
+    }
+
+    /// The path of a data file or directory
+    pub fn data_path<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        SETTINGS.lock().unwrap().data_path(file)
+    }
+
+    fn get(&self, key: &str) -> Option<Value> {
+        SETTINGS.lock().unwrap().plugin_value(self.plugin, key)
+    }
+
+    pub fn get_bool(&self, key: &str) -> bool {
+        self.get(key)
+            .and_then(|value| value.as_bool())
+            .unwrap_or(false)
+    }
+
+    pub fn get_integer(&self, key: &str) -> i64 {
+        self.get(key)
+            .and_then(|value| value.as_integer())
+            .unwrap_or(0)
+    }
+
+    fn set(&self, key: &str, value: Value) -> Result<(), String> {
+        SETTINGS
+            .lock()
+            .unwrap()
+            .set_plugin_value(self.plugin, key, value)
+    }
+
+    pub fn set_bool(&self, key: &str, value: bool) -> Result<(), String> {
+        self.set(key, Value::Boolean(value))
     }
 }
 
 lazy_static! {
-    pub static ref SETTINGS : ::std::sync::Mutex<Settings> = ::std::sync::Mutex::new(Settings::from_file("settings.ini"));
+    pub static ref SETTINGS : ::std::sync::Mutex<Settings> = ::std::sync::Mutex::new(Settings::new(DEFAULT_PATH));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn parse(source: &str) -> Result<Settings, ConfigError> {
+        Settings::parse(Path::new("settings.toml"), source)
+    }
+
+    fn parse_error(source: &str) -> ConfigError {
+        match parse(source) {
+            Ok(_) => panic!("expected an error for:\n{}", source),
+            Err(e) => e,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lucidbot-settings-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_core_and_plugin_settings() {
+        let settings = parse("[core]\n\
+                              command_char = \"?\"\n\
+                              admins = [\"alice\", \"bob\"]\n\
+                              tick_interval = 5\n\
+                              log_timezone = \"+02:00\"\n\
+                              \n\
+                              [plugins.randomchat]\n\
+                              time_budget = 50\n\
+                              channels_deny = [\"general\"]\n\
+                              probability = 3\n")
+                .unwrap();
+        assert_eq!(settings.core.command_char, "?");
+        assert_eq!(settings.core.admins, vec!["alice", "bob"]);
+        assert_eq!(settings.core.tick_interval, 5);
+        assert_eq!(settings.core.backend, "slack");
+        assert_eq!(settings.core.log_timezone.to_string(), "+02:00");
+        assert_eq!(settings.plugin("randomchat").time_budget, Some(50));
+        assert_eq!(settings.plugin("randomchat").channels_deny, vec!["general"]);
+        assert_eq!(settings.plugin_value("randomchat", "probability"),
+                   Some(Value::Integer(3)));
+    }
+
+    #[test]
+    fn defaults_without_a_core_section() {
+        let settings = parse("").unwrap();
+        assert_eq!(settings.core.command_char, "!");
+        assert_eq!(settings.core.plugin_time_budget, 1000);
+    }
+
+    #[test]
+    fn reports_unknown_keys_with_their_line() {
+        let e = parse_error("[core]\ncommand_char = \"!\"\ncomand_char = \"?\"\n");
+        assert_eq!(e.line, Some(3));
+        assert!(e.message.starts_with("core.comand_char: unknown setting"),
+                "{}",
+                e.message);
+
+        let e = parse_error("[core]\n\n[plugin.randomchat]\n");
+        assert_eq!(e.line, Some(3));
+        assert!(e.message.starts_with("plugin: unknown setting"), "{}", e.message);
+    }
+
+    #[test]
+    fn reports_type_errors_with_their_line() {
+        let e = parse_error("[core]\nbackend = \"console\"\ntick_interval = \"5\"\n");
+        assert_eq!(e.line, Some(3));
+        assert_eq!(e.message, "core.tick_interval: expected an integer, found string");
+
+        let e = parse_error("[core]\nwatch_files = 1\n");
+        assert_eq!(e.line, Some(2));
+        assert_eq!(e.message, "core.watch_files: expected true or false, found integer");
+
+        let e = parse_error("[core]\n\n[plugins.eightball]\nchannels_allow = [1]\n");
+        assert_eq!(e.line, Some(4));
+        assert_eq!(e.message,
+                   "plugins.eightball.channels_allow: expected a list of strings, found a \
+                    integer in it");
+        assert_eq!(e.to_string(),
+                   format!("settings.toml:4: {}", e.message));
+    }
+
+    #[test]
+    fn reports_invalid_values_with_their_line() {
+        let e = parse_error("[core]\nlog_rollover_hour = 24\n");
+        assert_eq!(e.line, Some(2));
+        assert_eq!(e.message, "core.log_rollover_hour: 24 is not between 0 and 23");
+
+        let e = parse_error("[core]\ntick_interval = 0\n");
+        assert_eq!(e.message, "core.tick_interval: 0 is less than 1");
+
+        let e = parse_error("[core]\ncommand_char = \"\"\n");
+        assert_eq!(e.message, "core.command_char: must not be empty");
+
+        let e = parse_error("[core]\n\nbackend = \"irc\"\n");
+        assert_eq!(e.line, Some(3));
+        assert_eq!(e.message,
+                   "core.backend: unknown backend irc, expected slack or console");
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_their_line() {
+        let e = parse_error("[core]\ncommand_char = \"!\"\ntick_interval = \n");
+        assert_eq!(e.line, Some(3));
+    }
+
+    #[test]
+    fn checks_plugin_settings_once_declared() {
+        let source = "[plugins.randomchat]\nprobability = \"often\"\n";
+        let mut settings = parse(source).unwrap();
+        let e = settings
+            .register("randomchat",
+                      vec![SettingSpec::integer("probability", 1, 0, 100, "")])
+            .unwrap_err();
+        assert_eq!(e.line, Some(2));
+        assert_eq!(e.message,
+                   "plugins.randomchat.probability: expected an integer from 0 to 100, found \
+                    string");
+
+        let mut settings = parse("[plugins.randomchat]\n\nprobabilty = 5\n").unwrap();
+        let e = settings
+            .register("randomchat",
+                      vec![SettingSpec::integer("probability", 1, 0, 100, "")])
+            .unwrap_err();
+        assert_eq!(e.line, Some(3));
+        assert!(e.message
+                    .starts_with("plugins.randomchat.probabilty: unknown setting"),
+                "{}",
+                e.message);
+    }
+
+    #[test]
+    fn treats_unknown_and_token_like_settings_as_secret() {
+        let mut settings = parse("").unwrap();
+        settings
+            .register("weather",
+                      vec![SettingSpec::string("city", "Paris", "The default city"),
+                           SettingSpec::string("api_token", "", "Named like a secret")])
+            .unwrap();
+        assert!(settings.is_secret("core.token"));
+        assert!(settings.is_secret("core.tokne"));
+        assert!(settings.is_secret("weather.api_token"));
+        assert!(settings.is_secret("weather.password"));
+        assert!(settings.is_secret("nosuchplugin.city"));
+        assert!(!settings.is_secret("weather.city"));
+        assert!(!settings.is_secret("core.tick_interval"));
+    }
+
+    #[test]
+    fn hides_secret_plugin_settings() {
+        let mut settings = parse("[plugins.weather]\n\
+                                  api_key = \"abc123\"\n\
+                                  city = \"Oslo\"\n")
+                .unwrap();
+        settings
+            .register("weather",
+                      vec![SettingSpec::string("api_key", "", "The API key").secret(),
+                           SettingSpec::string("city", "Paris", "The default city")])
+            .unwrap();
+        let described = settings.describe_plugin("weather");
+        assert_eq!(described,
+                   vec!["weather.api_key = (hidden) (a string, default (not set)) - The API key",
+                        "weather.city = \"Oslo\" (a string, default \"Paris\") - The default \
+                         city"]);
+    }
+
+    #[test]
+    fn falls_back_to_the_newest_valid_backup() {
+        let dir = temp_dir("backup");
+        let path = dir.join(DEFAULT_PATH);
+        fs::write(&path, "[core]\ncommand_char = 1\n").unwrap();
+        let e = match Settings::load_or_backup(&path) {
+            Ok(_) => panic!("expected an error without backups"),
+            Err(e) => e,
+        };
+        assert_eq!(e.path, path);
+        assert_eq!(e.line, Some(2));
+
+        fs::write(persist::backup_path(&path, 1), "[core\n").unwrap();
+        fs::write(persist::backup_path(&path, 2),
+                  "[core]\ncommand_char = \"?\"\n")
+                .unwrap();
+        fs::write(persist::backup_path(&path, 3),
+                  "[core]\ncommand_char = \"$\"\n")
+                .unwrap();
+        let (settings, backup) = Settings::load_or_backup(&path).unwrap();
+        assert_eq!(backup, Some(persist::backup_path(&path, 2)));
+        assert_eq!(settings.core.command_char, "?");
+        assert_eq!(settings.path(), path.as_path());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn migrates_settings_ini() {
+        let dir = temp_dir("migrate");
+        let legacy = dir.join(LEGACY_FILE);
+        let path = dir.join(DEFAULT_PATH);
+        fs::write(&legacy,
+                  "\"token\" : \"xoxb-1\"\n\
+                   \"command_char\" : \"?\"\n\
+                   \"admins\" : \"alice, bob\"\n\
+                   \"tick_interval\" : \"30\"\n\
+                   \"plugin_time_budget\" : \"soon\"\n\
+                   \"randomchat_enabled\" : \"true\"\n\
+                   \"randomchat_probability\" : \"7\"\n\
+                   \"eightball_channels_deny\" : \"general,random\"\n\
+                   \"colour\" : \"blue\"\n")
+                .unwrap();
+
+        let (settings, skipped) = Settings::migrate(&legacy, &path).unwrap();
+        assert_eq!(skipped, vec!["plugin_time_budget", "colour"]);
+        assert_eq!(settings.core.token, "xoxb-1");
+        assert_eq!(settings.core.command_char, "?");
+        assert_eq!(settings.core.admins, vec!["alice", "bob"]);
+        assert_eq!(settings.core.tick_interval, 30);
+        assert_eq!(settings.core.plugin_time_budget, 1000);
+
+        // the migrated file reads back the same
+        let settings = Settings::from_file(&path).unwrap();
+        assert_eq!(settings.core.token, "xoxb-1");
+        assert_eq!(settings.core.tick_interval, 30);
+        assert_eq!(settings.plugin_value("randomchat", "enabled"),
+                   Some(Value::Boolean(true)));
+        assert_eq!(settings.plugin_value("randomchat", "probability"),
+                   Some(Value::Integer(7)));
+        assert_eq!(settings.plugin("eightball").channels_deny,
+                   vec!["general", "random"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,142 @@
+//! Stopping the bot on SIGINT and SIGTERM, so that the plugins get to save their data.
+
+use backend::ChatBackend;
+use libc;
+use queue::EventQueue;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// How often, in milliseconds, the main thread checks whether to stop
+const POLL_INTERVAL: u64 = 100;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT and SIGTERM request a shutdown instead of killing the bot
+pub fn install_handlers() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Whether a signal has asked the bot to shut down
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Runs the backend on its own thread, reconnecting after its delay, until it stops for
+/// good or `stop` returns true. A backend still blocked in `run` is left to end with the
+/// process.
+pub fn run_backend(backend: Arc<ChatBackend>, mut queue: EventQueue, stop: fn() -> bool) {
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            if let Err(e) = backend.run(&mut queue) {
+                println!("{}", e);
+            }
+            match backend.reconnect_delay() {
+                Some(delay) => thread::sleep(delay),
+                None => break,
+            }
+        }
+        let _ = done_tx.send(());
+    });
+
+    while !stop() {
+        match done_rx.recv_timeout(Duration::from_millis(POLL_INTERVAL)) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) |
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, Channel, ChannelKind, EventHandler, User};
+
+    /// Runs until the process ends, like a connection that is never closed, or returns
+    /// right away for good
+    struct FakeBackend {
+        forever: bool,
+    }
+
+    impl ChatBackend for FakeBackend {
+        fn run(&self, _: &mut EventHandler) -> Result<(), BackendError> {
+            while self.forever {
+                thread::park();
+            }
+            Ok(())
+        }
+
+        fn reconnect_delay(&self) -> Option<Duration> {
+            if self.forever {
+                Some(Duration::from_millis(1))
+            } else {
+                None
+            }
+        }
+
+        fn send_message(&self, _: &str, _: &str) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn send_direct(&self, _: &str, _: &str) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn send_in_thread(&self, _: &str, _: &str, _: &str) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn add_reaction(&self, _: &str, _: &str, _: &str) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn self_user(&self) -> Option<User> {
+            None
+        }
+
+        fn users(&self) -> Vec<User> {
+            Vec::new()
+        }
+
+        fn channels(&self) -> Vec<Channel> {
+            Vec::new()
+        }
+
+        fn channel_kind(&self, _: &str) -> ChannelKind {
+            ChannelKind::Public
+        }
+    }
+
+    fn never() -> bool {
+        false
+    }
+
+    #[test]
+    fn returns_when_the_backend_stops_for_good() {
+        let (queue, _events) = EventQueue::new();
+        run_backend(Arc::new(FakeBackend { forever: false }), queue, never);
+    }
+
+    #[test]
+    fn sigterm_stops_a_backend_which_runs_forever() {
+        install_handlers();
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        assert!(requested());
+        let (queue, _events) = EventQueue::new();
+        run_backend(Arc::new(FakeBackend { forever: true }), queue, requested);
+    }
+}
@@ -0,0 +1,379 @@
+use {BotEvent, MessageData};
+use backend::{Event, EventKind};
+use command::{Command, CommandSpec};
+use plugin::Plugin;
+use scheduler::JobHandle;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// An owned copy of `MessageData` that can be sent to a worker thread
+struct MessageContent {
+    self_name: String,
+    user: String,
+    channel: String,
+    msg: String,
+}
+
+/// How long a plugin gets to shut down, regardless of its budget
+const SHUTDOWN_TIMEOUT: u64 = 10;
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+enum Request {
+    Priority(String, String, String),
+    Message(MessageContent),
+    Command(String, String, Command),
+    Event(Event),
+    Connect,
+    Disconnect,
+    Tick,
+    Scheduled(JobHandle),
+    Reload,
+    Shutdown,
+}
+
+impl Request {
+    fn describe(&self) -> &'static str {
+        match *self {
+            Request::Priority(..) => "priority",
+            Request::Message(_) => "message",
+            Request::Command(..) => "command",
+            Request::Event(_) => "event",
+            Request::Connect => "connect",
+            Request::Disconnect => "disconnect",
+            Request::Tick => "tick",
+            Request::Scheduled(_) => "scheduled job",
+            Request::Reload => "reload",
+            Request::Shutdown => "shutdown",
+        }
+    }
+}
+
+enum Response {
+    Priority(i16),
+    Event(BotEvent),
+    Reloaded(Result<(), String>),
+    Done,
+}
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The plugin didn't respond within its time budget
+    Timeout(Duration),
+    /// The plugin is still handling a request which timed out, so it wasn't asked
+    Busy,
+    /// The worker answered a request of the given kind with the wrong kind of response
+    UnexpectedResponse(&'static str),
+    /// The worker thread is gone, most likely because the plugin panicked
+    Stopped,
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WorkerError::Timeout(budget) => {
+                write!(f, "didn't respond within {} ms", millis(budget))
+            }
+            WorkerError::Busy => write!(f, "is still busy with an earlier request"),
+            WorkerError::UnexpectedResponse(kind) => {
+                write!(f, "gave an unexpected response to a {} request", kind)
+            }
+            WorkerError::Stopped => write!(f, "has stopped"),
+        }
+    }
+}
+
+fn handle_request(plugin: &mut Plugin, request: Request) -> Response {
+    match request {
+        Request::Priority(user, channel, msg) => {
+            Response::Priority(plugin.plugin_priority(&user, &channel, &msg))
+        }
+        Request::Message(content) => {
+            let data = MessageData {
+                self_name: &content.self_name,
+                user: &content.user,
+                channel: &content.channel,
+                msg: &content.msg,
+            };
+            Response::Event(plugin.handle_message(data))
+        }
+        Request::Command(user, channel, command) => {
+            Response::Event(plugin.handle_command(&user, &channel, &command))
+        }
+        Request::Event(event) => Response::Event(plugin.handle_event(&event)),
+        Request::Connect => Response::Event(plugin.on_connect()),
+        Request::Disconnect => {
+            plugin.on_disconnect();
+            Response::Done
+        }
+        Request::Tick => {
+            plugin.on_tick();
+            Response::Done
+        }
+        Request::Scheduled(handle) => Response::Event(plugin.on_scheduled(handle)),
+        Request::Reload => Response::Reloaded(plugin.reload()),
+        Request::Shutdown => {
+            plugin.on_shutdown();
+            Response::Done
+        }
+    }
+}
+
+/// Runs a plugin on its own thread.
+///
+/// Every call waits for the plugin at most for its time budget. Results that
+/// arrive later are dropped, and a note about them is sent to the `late` channel.
+/// Until then the plugin is skipped, rather than making every call wait out the budget.
+pub struct PluginWorker {
+    name: &'static str,
+    commands: Vec<CommandSpec>,
+    subscriptions: Vec<EventKind>,
+    data_files: Vec<PathBuf>,
+    budget: Duration,
+    /// The number of requests sent to the thread and not handled yet
+    pending: Arc<AtomicUsize>,
+    requests: Option<Sender<(Request, Sender<Response>)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PluginWorker {
+    pub fn spawn(mut plugin: Box<Plugin>, budget: Duration, late: Sender<String>) -> PluginWorker {
+        let name = plugin.plugin_name();
+        let commands = plugin.commands();
+        let subscriptions = plugin.subscriptions();
+        let data_files = plugin.data_files();
+        let (tx, rx): (_, Receiver<(Request, Sender<Response>)>) = mpsc::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let thread_pending = pending.clone();
+        let thread = thread::spawn(move || {
+            for (request, reply) in rx {
+                let started = Instant::now();
+                let kind = request.describe();
+                let response = handle_request(&mut *plugin, request);
+                thread_pending.fetch_sub(1, Ordering::SeqCst);
+                if reply.send(response).is_err() {
+                    let _ = late.send(format!("Dropped a late {} result from {} after {} ms",
+                                              kind,
+                                              name,
+                                              millis(started.elapsed())));
+                }
+            }
+        });
+
+        PluginWorker {
+            name: name,
+            commands: commands,
+            subscriptions: subscriptions,
+            data_files: data_files,
+            budget: budget,
+            pending: pending,
+            requests: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn commands(&self) -> &[CommandSpec] {
+        &self.commands
+    }
+
+    pub fn is_subscribed(&self, kind: EventKind) -> bool {
+        self.subscriptions.contains(&kind)
+    }
+
+    pub fn data_files(&self) -> &[PathBuf] {
+        &self.data_files
+    }
+
+    pub fn set_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    fn call(&self, request: Request) -> Result<Response, WorkerError> {
+        let requests = self.requests.as_ref().ok_or(WorkerError::Stopped)?;
+        if self.pending.load(Ordering::SeqCst) > 0 {
+            return Err(WorkerError::Busy);
+        }
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if requests.send((request, reply_tx)).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(WorkerError::Stopped);
+        }
+        reply_rx
+            .recv_timeout(self.budget)
+            .map_err(|e| match e {
+                         RecvTimeoutError::Timeout => WorkerError::Timeout(self.budget),
+                         RecvTimeoutError::Disconnected => WorkerError::Stopped,
+                     })
+    }
+
+    fn call_event(&self, request: Request) -> Result<BotEvent, WorkerError> {
+        let kind = request.describe();
+        match self.call(request)? {
+            Response::Event(event) => Ok(event),
+            _ => Err(WorkerError::UnexpectedResponse(kind)),
+        }
+    }
+
+    pub fn priority(&self, user: &str, channel: &str, msg: &str) -> Result<i16, WorkerError> {
+        let request = Request::Priority(user.to_string(), channel.to_string(), msg.to_string());
+        match self.call(request)? {
+            Response::Priority(priority) => Ok(priority),
+            _ => Err(WorkerError::UnexpectedResponse("priority")),
+        }
+    }
+
+    pub fn handle_message(&self, data: MessageData) -> Result<BotEvent, WorkerError> {
+        self.call_event(Request::Message(MessageContent {
+                                             self_name: data.self_name.to_string(),
+                                             user: data.user.to_string(),
+                                             channel: data.channel.to_string(),
+                                             msg: data.msg.to_string(),
+                                         }))
+    }
+
+    pub fn handle_command(&self,
+                          user: &str,
+                          channel: &str,
+                          command: Command)
+                          -> Result<BotEvent, WorkerError> {
+        self.call_event(Request::Command(user.to_string(), channel.to_string(), command))
+    }
+
+    pub fn handle_event(&self, event: Event) -> Result<BotEvent, WorkerError> {
+        self.call_event(Request::Event(event))
+    }
+
+    pub fn on_connect(&self) -> Result<BotEvent, WorkerError> {
+        self.call_event(Request::Connect)
+    }
+
+    pub fn on_disconnect(&self) -> Result<(), WorkerError> {
+        self.call(Request::Disconnect).map(|_| ())
+    }
+
+    pub fn on_tick(&self) -> Result<(), WorkerError> {
+        self.call(Request::Tick).map(|_| ())
+    }
+
+    pub fn on_scheduled(&self, handle: JobHandle) -> Result<BotEvent, WorkerError> {
+        self.call_event(Request::Scheduled(handle))
+    }
+
+    /// Returns the plugin's own error if it couldn't reload its data
+    pub fn reload(&self) -> Result<Result<(), String>, WorkerError> {
+        match self.call(Request::Reload)? {
+            Response::Reloaded(result) => Ok(result),
+            _ => Err(WorkerError::UnexpectedResponse("reload")),
+        }
+    }
+
+    /// Lets the plugin shut down and waits for its thread to finish, for at most
+    /// `SHUTDOWN_TIMEOUT` seconds regardless of the budget; a plugin which takes longer is
+    /// left running
+    pub fn shutdown(&mut self) -> Result<(), WorkerError> {
+        let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT);
+        if let Some(requests) = self.requests.take() {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            if requests.send((Request::Shutdown, reply_tx)).is_ok() {
+                match reply_rx.recv_timeout(timeout) {
+                    Ok(_) |
+                    Err(RecvTimeoutError::Disconnected) => {}
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.thread = None;
+                        return Err(WorkerError::Timeout(timeout));
+                    }
+                }
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PluginWorker {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {BotEvent, MessageData, ResumeEventHandling};
+
+    /// Tells its priority once the test opens the gate, and reports when it starts and
+    /// finishes
+    struct Gated {
+        gate: Receiver<()>,
+        events: Sender<&'static str>,
+    }
+
+    impl Plugin for Gated {
+        fn plugin_name(&self) -> &'static str {
+            "gated"
+        }
+
+        fn plugin_priority(&self, _: &str, _: &str, _: &str) -> i16 {
+            self.events.send("started").unwrap();
+            self.gate.recv().unwrap();
+            self.events.send("finished").unwrap();
+            1
+        }
+
+        fn handle_message(&mut self, _: MessageData) -> BotEvent {
+            BotEvent::None(ResumeEventHandling::Resume)
+        }
+    }
+
+    #[test]
+    fn skips_a_plugin_until_its_late_request_is_done() {
+        let (gate_tx, gate_rx) = mpsc::channel();
+        let (events_tx, events_rx) = mpsc::channel();
+        let (late_tx, late_rx) = mpsc::channel();
+        let plugin = Gated {
+            gate: gate_rx,
+            events: events_tx,
+        };
+        let worker = PluginWorker::spawn(Box::new(plugin), Duration::from_millis(200), late_tx);
+
+        match worker.priority("user", "channel", "msg") {
+            Err(WorkerError::Timeout(_)) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert_eq!(events_rx.recv().unwrap(), "started");
+        match worker.priority("user", "channel", "msg") {
+            Err(WorkerError::Busy) => {}
+            other => panic!("expected the worker to be busy, got {:?}", other),
+        }
+        // the busy plugin wasn't asked again
+        assert!(events_rx.try_recv().is_err());
+
+        gate_tx.send(()).unwrap();
+        assert_eq!(events_rx.recv().unwrap(), "finished");
+        // the dropped result is reported after the request is counted as done
+        late_rx.recv().unwrap();
+
+        gate_tx.send(()).unwrap();
+        match worker.priority("user", "channel", "msg") {
+            Ok(1) => {}
+            other => panic!("expected the plugin to be asked again, got {:?}", other),
+        }
+        assert_eq!(events_rx.try_iter().collect::<Vec<_>>(), vec!["started", "finished"]);
+    }
+}